@@ -0,0 +1,33 @@
+#!/bin/sh
+set -eu
+
+# Produce BakerCM ciphertexts for the decrypts_bakercm_values_with_either_separator test
+# in src/secret.rs. Installs bakercm into a throwaway virtualenv and HOME, generates the
+# key from each key pass with `baker genkey` and encrypts the test plaintexts with
+# `baker encrypt`. Paste the printed values into BAKERCM_VECTORS together with the header.
+# Requires: python3 with venv, network access to PyPI.
+
+VERSION="${BAKERCM_VERSION:-}"
+WORK="$(mktemp -d)"
+trap 'rm -rf "$WORK"' EXIT
+
+python3 -m venv "$WORK/venv"
+"$WORK/venv/bin/pip" install --quiet "bakercm${VERSION:+==$VERSION}"
+BAKER="$WORK/venv/bin/baker"
+
+echo "# bakercm $("$WORK/venv/bin/pip" show bakercm | sed -n 's/^Version: //p')"
+echo "# HOME=<tmp> baker genkey <key pass> && baker encrypt <plaintext>"
+
+vector() {
+	HOME="$WORK/home-$1" && export HOME
+	mkdir -p "$HOME"
+	if [ ! -f "$HOME/.baker/baker.key" ]; then
+		"$BAKER" genkey "$2" >/dev/null
+	fi
+	printf '%s\t%s\n' "$2" "$3"
+	"$BAKER" encrypt "$3"
+}
+
+vector 1 "bakercm" "postgres-password"
+vector 1 "bakercm" "tab separated"
+vector 2 "another key pass" "p@ss w0rd/ünïcode"
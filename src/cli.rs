@@ -1,10 +1,16 @@
+use crate::input::SecretInput;
 use crate::logger::Logger;
+use crate::secret::KeySource;
+use crate::sensitive::SecretString;
 use crate::settings::VERSION;
+use crate::utils::hex_decode;
 use std::error::Error;
 
 #[derive(Debug, Clone)]
 pub struct Options {
     pub verbose: bool,
+    pub recipe_format: Option<String>,
+    pub key_file: Option<String>,
     pub command: Command,
 }
 
@@ -12,13 +18,41 @@ pub struct Options {
 pub enum Command {
     Help,
     Version,
+    Bind { paths: Vec<String>, dry_run: bool },
     Configs { all: bool },
-    Encrypt { plantexts: Vec<String>, file: Option<String> },
-    GenKey { keypass: String },
+    Doctor,
+    Decrypt { ciphertexts: Vec<String>, file: Option<String> },
+    Edit { path: String },
+    Encrypt {
+        plantexts: Vec<SecretString>,
+        input: Option<SecretInput>,
+        file: Option<String>,
+        key_id: Option<String>,
+        format: Option<String>,
+    },
+    GenKey {
+        key: KeyOptions,
+        identity: bool,
+        /// Replace an existing key file.
+        force: bool,
+    },
+    Lint { paths: Vec<String> },
     Pull { name: String, force: bool },
     Recipes { all: bool },
+    Rekey {
+        key: KeyOptions,
+        paths: Vec<String>,
+        dry_run: bool,
+    },
+    /// `rekey --recipients`: encrypt recipient secrets again to the listed recipients.
+    Rewrap { paths: Vec<String>, dry_run: bool },
     Rm { recipe_id: String },
-    Run { name: Option<String>, path: Option<String>, force: bool },
+    Run {
+        name: Option<String>,
+        path: Option<String>,
+        env: Option<String>,
+        force: bool,
+    },
 }
 
 pub fn parse(args: &[String], logger: &Logger) -> Result<Options, Box<dyn Error>> {
@@ -27,6 +61,8 @@ pub fn parse(args: &[String], logger: &Logger) -> Result<Options, Box<dyn Error>
         print_help();
         return Ok(Options {
             verbose: false,
+            recipe_format: None,
+            key_file: None,
             command: Command::Help,
         });
     }
@@ -41,37 +77,147 @@ pub fn parse(args: &[String], logger: &Logger) -> Result<Options, Box<dyn Error>
         }
     });
 
+    let mut recipe_format = None;
+    if let Some(pos) = args.iter().position(|a| a == "--recipe-format") {
+        args.remove(pos);
+        if pos >= args.len() {
+            return Err("--recipe-format expects ini, toml, yaml or json".into());
+        }
+        recipe_format = Some(args.remove(pos));
+    }
+    let mut key_file = None;
+    if let Some(pos) = args.iter().position(|a| a == "--key-file") {
+        args.remove(pos);
+        if pos >= args.len() {
+            return Err("--key-file expects a path".into());
+        }
+        key_file = Some(args.remove(pos));
+    }
+    if args.is_empty() {
+        print_help();
+        return Ok(Options {
+            verbose,
+            recipe_format,
+            key_file,
+            command: Command::Help,
+        });
+    }
+
     let cmd = match args[0].as_str() {
         "-h" | "--help" => Command::Help,
         "-v" | "--version" => Command::Version,
+        "bind" => {
+            let dry_run = args.iter().any(|a| a == "-n" || a == "--dry-run");
+            let paths: Vec<String> = args[1..]
+                .iter()
+                .filter(|a| *a != "-n" && *a != "--dry-run")
+                .cloned()
+                .collect();
+            if paths.is_empty() {
+                return Err("bind expects at least one recipe file or directory".into());
+            }
+            Command::Bind { paths, dry_run }
+        }
         "configs" => Command::Configs {
-            all: args.get(1).map_or(false, |v| v == "-a" || v == "--all"),
+            all: args.get(1).is_some_and(|v| v == "-a" || v == "--all"),
         },
         "encrypt" => {
+            let mut rest = args[1..].to_vec();
+            let input = SecretInput::take(&mut rest, "value")?;
             let mut file = None;
+            let mut key_id = None;
+            let mut format = None;
             let mut plantexts = Vec::new();
+            let mut idx = 0;
+            while idx < rest.len() {
+                if rest[idx] == "--file" {
+                    idx += 1;
+                    file = Some(
+                        rest.get(idx)
+                            .ok_or("encrypt --file expects a path value")?
+                            .to_string(),
+                    );
+                } else if rest[idx] == "--key-id" {
+                    idx += 1;
+                    key_id = Some(
+                        rest.get(idx)
+                            .ok_or("encrypt --key-id expects a key ring id")?
+                            .to_string(),
+                    );
+                } else if rest[idx] == "--format" {
+                    idx += 1;
+                    format = Some(
+                        rest.get(idx)
+                            .ok_or("encrypt --format expects legacy or v2")?
+                            .to_string(),
+                    );
+                } else {
+                    plantexts.push(rest[idx].as_str().into());
+                }
+                idx += 1;
+            }
+            Command::Encrypt {
+                plantexts,
+                input,
+                file,
+                key_id,
+                format,
+            }
+        }
+        "doctor" => Command::Doctor,
+        "decrypt" => {
+            let mut file = None;
+            let mut ciphertexts = Vec::new();
             let mut idx = 1;
             while idx < args.len() {
                 if args[idx] == "--file" {
                     idx += 1;
                     file = Some(
                         args.get(idx)
-                            .ok_or("encrypt --file expects a path value")?
+                            .ok_or("decrypt --file expects a path value")?
                             .to_string(),
                     );
                 } else {
-                    plantexts.push(args[idx].to_string());
+                    ciphertexts.push(args[idx].to_string());
                 }
                 idx += 1;
             }
-            Command::Encrypt { plantexts, file }
+            Command::Decrypt { ciphertexts, file }
+        }
+        "edit" => {
+            let path = args.get(1).ok_or("edit expects <recipe> argument")?.to_string();
+            Command::Edit { path }
         }
         "genkey" => {
-            let keypass = args
-                .get(1)
-                .ok_or("genkey expects <keypass> argument")?
-                .to_string();
-            Command::GenKey { keypass }
+            let mut rest = args[1..].to_vec();
+            let identity = rest.iter().any(|a| a == "--identity");
+            let force = rest.iter().any(|a| a == "-f" || a == "--force");
+            rest.retain(|a| a != "--identity" && a != "-f" && a != "--force");
+            let key = if identity {
+                // An identity is random; there is no key pass to read.
+                KeyOptions {
+                    random: true,
+                    ..key_options(&mut rest, "genkey", false)?
+                }
+            } else {
+                key_options(&mut rest, "genkey", true)?
+            };
+            Command::GenKey {
+                key,
+                identity,
+                force,
+            }
+        }
+        "lint" => {
+            let paths: Vec<String> = args[1..]
+                .iter()
+                .filter(|a| a.as_str() != "--path")
+                .cloned()
+                .collect();
+            if paths.is_empty() {
+                return Err("lint expects at least one recipe path".into());
+            }
+            Command::Lint { paths }
         }
         "pull" => {
             let name = args.get(1).ok_or("pull expects <name> argument")?.to_string();
@@ -81,6 +227,40 @@ pub fn parse(args: &[String], logger: &Logger) -> Result<Options, Box<dyn Error>
         "recipes" => Command::Recipes {
             all: args.iter().any(|a| a == "-a" || a == "--all"),
         },
+        "rekey" => {
+            let mut rest = args[1..].to_vec();
+            let recipients = rest.iter().any(|a| a == "--recipients");
+            rest.retain(|a| a != "--recipients");
+            let key = key_options(&mut rest, "rekey", false)?;
+            let dry_run = rest.iter().any(|a| a == "-n" || a == "--dry-run");
+            let paths: Vec<String> = rest
+                .into_iter()
+                .filter(|a| a != "-n" && a != "--dry-run")
+                .collect();
+            if paths.is_empty() {
+                return Err("rekey expects at least one recipe file or directory".into());
+            }
+            if recipients {
+                let keyless = KeyOptions {
+                    id: None,
+                    random: false,
+                    kdf: None,
+                    salt: None,
+                    params: None,
+                    keypass: SecretInput::Prompt,
+                };
+                if key != keyless {
+                    return Err("rekey --recipients does not take key options".into());
+                }
+                Command::Rewrap { paths, dry_run }
+            } else {
+                Command::Rekey {
+                    key,
+                    paths,
+                    dry_run,
+                }
+            }
+        }
         "rm" => {
             let recipe_id = args.get(1).ok_or("rm expects <recipe_id>")?.to_string();
             Command::Rm { recipe_id }
@@ -88,6 +268,7 @@ pub fn parse(args: &[String], logger: &Logger) -> Result<Options, Box<dyn Error>
         "run" => {
             let mut name: Option<String> = None;
             let mut path: Option<String> = None;
+            let mut env: Option<String> = None;
             let mut force = false;
             let mut idx = 1;
             while idx < args.len() {
@@ -101,6 +282,14 @@ pub fn parse(args: &[String], logger: &Logger) -> Result<Options, Box<dyn Error>
                                 .to_string(),
                         );
                     }
+                    "-e" | "--env" => {
+                        idx += 1;
+                        env = Some(
+                            args.get(idx)
+                                .ok_or("run --env expects an environment name")?
+                                .to_string(),
+                        );
+                    }
                     other => {
                         if !other.starts_with('-') && name.is_none() {
                             name = Some(other.to_string());
@@ -112,7 +301,12 @@ pub fn parse(args: &[String], logger: &Logger) -> Result<Options, Box<dyn Error>
             if name.is_some() && path.is_some() {
                 return Err("run does not support both name and --path together".into());
             }
-            Command::Run { name, path, force }
+            Command::Run {
+                name,
+                path,
+                env,
+                force,
+            }
         }
         other => {
             logger.log(&format!("Unknown command '{}'", other));
@@ -122,15 +316,128 @@ pub fn parse(args: &[String], logger: &Logger) -> Result<Options, Box<dyn Error>
 
     Ok(Options {
         verbose,
+        recipe_format,
+        key_file,
         command: cmd,
     })
 }
 
+/// How `genkey` and `rekey` build a key; the key pass is read only when the command runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyOptions {
+    /// Key ring id; `None` is the default key.
+    pub id: Option<String>,
+    pub random: bool,
+    pub kdf: Option<String>,
+    pub salt: Option<String>,
+    /// Argon2id costs, `m=<KiB>,t=<N>,p=<N>`.
+    pub params: Option<String>,
+    pub keypass: SecretInput,
+}
+
+impl KeyOptions {
+    pub fn source(&self) -> Result<KeySource, Box<dyn Error>> {
+        if self.random {
+            return Ok(KeySource::Random);
+        }
+        let keypass = self.keypass.read("Key pass", true)?;
+        if keypass.is_empty() {
+            return Err("Key pass is empty".into());
+        }
+        // BakerCM's unsalted SHA-256 stays the default so the same key pass gives the same
+        // key on every host; a salt or params only make sense with Argon2id.
+        let argon = self.salt.is_some() || self.params.is_some();
+        let default = if argon { "argon2id" } else { "sha256" };
+        match self.kdf.as_deref().unwrap_or(default) {
+            "argon2id" => Ok(KeySource::Argon2id {
+                keypass,
+                salt: self.salt.as_deref().map(hex_decode).transpose()?,
+                params: self.params.clone(),
+            }),
+            "sha256" => Ok(KeySource::Legacy(keypass)),
+            other => Err(format!("Unknown --kdf '{}', expected argon2id or sha256", other).into()),
+        }
+    }
+}
+
+/// Takes the key options (`--key-id`, `--random`, `--kdf`, `--salt`, `--params`,
+/// `--keypass[-stdin|-fd|-file]`) out of `args`. With `positional`, the first remaining argument is the key pass when no other
+/// key pass input is given. Without any, the key pass is prompted for.
+fn key_options(
+    args: &mut Vec<String>,
+    command: &str,
+    positional: bool,
+) -> Result<KeyOptions, Box<dyn Error>> {
+    let mut input = SecretInput::take(args, "keypass")?;
+    let mut random = false;
+    let mut id = None;
+    let mut kdf = None;
+    let mut salt = None;
+    let mut params = None;
+    let mut rest = Vec::new();
+    let mut iter = args.drain(..);
+    while let Some(arg) = iter.next() {
+        let slot = match arg.as_str() {
+            "--random" => {
+                random = true;
+                continue;
+            }
+            "--key-id" => &mut id,
+            "--kdf" => &mut kdf,
+            "--salt" => &mut salt,
+            "--params" => &mut params,
+            "--keypass" => {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| format!("{} --keypass expects a value", command))?;
+                if input.replace(SecretInput::Arg(value.into())).is_some() {
+                    return Err("Only one key pass input can be given".into());
+                }
+                continue;
+            }
+            _ => {
+                rest.push(arg);
+                continue;
+            }
+        };
+        let value = iter
+            .next()
+            .ok_or_else(|| format!("{} {} expects a value", command, arg))?;
+        *slot = Some(value);
+    }
+    drop(iter);
+    if positional && input.is_none() && !random {
+        if let Some(pos) = rest.iter().position(|a| !a.starts_with('-')) {
+            input = Some(SecretInput::Arg(rest.remove(pos).into()));
+        }
+    }
+    *args = rest;
+
+    if random && (input.is_some() || kdf.is_some() || salt.is_some() || params.is_some()) {
+        let message = format!(
+            "{} --random does not take a key pass, --kdf, --salt or --params",
+            command
+        );
+        return Err(message.into());
+    }
+    if kdf.as_deref() == Some("sha256") && (salt.is_some() || params.is_some()) {
+        return Err(format!("{} --kdf sha256 does not use a salt or params", command).into());
+    }
+    Ok(KeyOptions {
+        id,
+        random,
+        kdf,
+        salt,
+        params,
+        keypass: input.unwrap_or(SecretInput::Prompt),
+    })
+}
+
 pub fn print_help() {
     println!(
         "baker-rust {}\n\
-usage: baker [--verbose] <COMMAND> ...\n\n\
-commands:\n  configs      list of configs\n  encrypt      encrypt values using secret key\n  genkey       generate a secret key from a key pass\n  pull         pull a recipe with configurations\n  recipes      list recipes locally\n  rm           remove recipes locally\n  run          run configurations from a recipe\n\n\
+usage: baker [--verbose] [--recipe-format FORMAT] [--key-file PATH] <COMMAND> ...\n\n\
+commands:\n  bind         bind recipe secrets to their instruction\n  configs      list of configs\n  decrypt      decrypt values or the secrets of a recipe\n  doctor       check secret key files and their permissions\n  edit         edit a recipe with its secrets decrypted\n  encrypt      encrypt values using secret key\n  genkey       generate a secret key from a key pass, or an identity\n  lint         check recipes without rendering them\n  pull         pull a recipe with configurations\n  recipes      list recipes locally\n  rekey        re-encrypt recipe secrets with a new key\n  rm           remove recipes locally\n  run          run configurations from a recipe\n\n\
 Run 'baker COMMAND --help' for more info on a command",
         VERSION
     );
@@ -1,20 +1,51 @@
-use crate::cli::{parse, Command};
+use crate::cli::{parse, Command, KeyOptions};
+use crate::doctor::doctor;
+use crate::edit::edit_recipe;
+use crate::input::SecretInput;
+use crate::lint::lint;
 use crate::logger::Logger;
-use crate::recipe::{decrypt_secrets, encrypt_recipe_file, parse as parse_recipe};
+use crate::recipe::{
+    decrypt_recipe_file, decrypt_secrets, encrypt_recipe_file, interpolate_variables,
+    parse as parse_recipe, RecipeError, RecipeFormat,
+};
+use crate::provider::{self, Providers};
+use crate::recipient::Identity;
+use crate::rekey::{bind, rekey, rewrap};
 use crate::repository::{download, ListRecipes, Repository};
-use crate::secret::{Crypto, SecretKey};
+use crate::secret::{CipherFormat, Crypto, KeyLocation, SecretKey};
 use crate::settings::Settings;
 use crate::template;
+use crate::utils::hex_encode;
 use std::error::Error;
 
 pub fn execute_command_line(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    match execute(args) {
+        Err(err) => match err.downcast_ref::<RecipeError>() {
+            Some(recipe_err) => {
+                eprintln!("{}", recipe_err);
+                eprintln!("    | {}", recipe_err.text.trim());
+                Err("Recipe is invalid".into())
+            }
+            None => Err(err),
+        },
+        ok => ok,
+    }
+}
+
+fn execute(args: Vec<String>) -> Result<(), Box<dyn Error>> {
     let mut args = args;
     if !args.is_empty() {
         args.remove(0); // binary name
     }
     let logger = Logger::new(false);
     let options = parse(&args, &logger)?;
-    let settings = Settings::load(options.verbose)?;
+    let mut settings = Settings::load(options.verbose)?;
+    if options.recipe_format.is_some() {
+        settings.recipe_format = options.recipe_format.clone();
+    }
+    if let Some(key_file) = &options.key_file {
+        settings.key_file = Some(key_file.into());
+    }
     let logger = Logger::new(settings.debug);
 
     match options.command {
@@ -24,33 +55,143 @@ pub fn execute_command_line(args: Vec<String>) -> Result<(), Box<dyn Error>> {
         Command::Version => {
             println!("baker-rust {}", crate::settings::VERSION);
         }
+        Command::Bind { paths, dry_run } => {
+            let crypto = load_crypto(&settings, &logger)?;
+            let summary = bind(&paths, &settings, &crypto, dry_run)?;
+            for file in &summary.files {
+                let action = if dry_run { "Would bind" } else { "Bound" };
+                logger.log(&format!("{} {}", action, file));
+            }
+            for file in &summary.ignored {
+                logger.warn(&format!("Skipped {}: not a recipe", file));
+            }
+            logger.log(&format!(
+                "{} secret(s) in {} file(s), {} already bound and {} plain secret(s) skipped",
+                summary.secrets,
+                summary.files.len(),
+                summary.bound,
+                summary.skipped
+            ));
+            if !settings.bind_secrets {
+                logger.warn(
+                    "BIND_SECRETS is off: new secrets are still written unbound and run \
+                     accepts unbound ones",
+                );
+            }
+        }
         Command::Configs { all } => {
             for (key, value) in settings.values(!all) {
                 logger.log(&format!("{}={}", key, value));
             }
         }
-        Command::Encrypt { plantexts, file } => {
-            let key = SecretKey::read(&settings)?;
-            let crypto = Crypto::new(key);
+        Command::Doctor => {
+            let checks = doctor(&settings);
+            for check in &checks {
+                logger.log(&format!("[{}] {}", check.level, check.message));
+            }
+            let errors = checks.iter().filter(|c| c.level == "error").count();
+            if errors > 0 {
+                return Err(format!("doctor found {} problem(s)", errors).into());
+            }
+        }
+        Command::Decrypt { ciphertexts, file } => {
+            let crypto = load_crypto(&settings, &logger)?;
             if let Some(path) = file {
-                encrypt_recipe_file(&path, &settings, &crypto)?;
-                logger.log("Secrets encrypted in recipe file");
+                for (instruction, key, plain) in decrypt_recipe_file(&path, &settings, &crypto)? {
+                    logger.log(&format!("[{}] {} = {}", instruction, key, *plain));
+                }
+            } else if !ciphertexts.is_empty() {
+                for cipher in ciphertexts {
+                    logger.log(&crypto.decrypt(&cipher)?);
+                }
+            } else {
+                return Err("decrypt expected at least one argument".into());
+            }
+        }
+        Command::Edit { path } => {
+            let crypto = load_crypto(&settings, &logger)?;
+            edit_recipe(&path, &settings, &crypto, &logger)?;
+        }
+        Command::Encrypt {
+            plantexts,
+            input,
+            file,
+            key_id,
+            format,
+        } => {
+            if let Some(format) = format {
+                settings.secret_format = format;
+            }
+            let crypto = load_crypto(&settings, &logger)?;
+            if let Some(path) = file {
+                let summary = encrypt_recipe_file(&path, &settings, &crypto)?;
+                logger.log(&format!(
+                    "Encrypted {} secret(s), skipped {} already encrypted",
+                    summary.encrypted, summary.skipped
+                ));
+                if summary.providers > 0 {
+                    logger.log(&format!(
+                        "Left {} provider reference(s) (file:, cmd:, env:) as they are",
+                        summary.providers
+                    ));
+                }
+                if summary.foreign > 0 {
+                    logger.warn(&format!(
+                        "{} skipped secret(s) do not decrypt with the current key",
+                        summary.foreign
+                    ));
+                }
             } else if !plantexts.is_empty() {
                 for text in plantexts {
-                    let cipher = crypto.encrypt(&text)?;
-                    logger.log(&format!("{} {}", text, cipher));
+                    let cipher = crypto.encrypt_with(key_id.as_deref(), &text)?;
+                    logger.log(&format!("{} {}", *text, cipher));
                 }
             } else {
-                return Err("encrypt expected at least one argument".into());
+                // Read values are never echoed back, only their ciphertext.
+                let text = input.unwrap_or(SecretInput::Prompt).read("Value", true)?;
+                logger.log(&crypto.encrypt_with(key_id.as_deref(), &text)?);
             }
         }
-        Command::GenKey { keypass } => {
-            let stored = SecretKey::generate(&keypass, &settings)?;
+        Command::GenKey {
+            identity: true, ..
+        } => {
+            let identity = Identity::generate();
+            SecretKey::save_identity(&identity, &settings.storage_identity)?;
+            logger.log(&format!(
+                "Generated identity and saved at '{}'",
+                settings.storage_identity.display()
+            ));
+            logger.log(&format!(
+                "Public key {} (share it to be added to RECIPIENTS)",
+                identity.recipient()
+            ));
+        }
+        Command::GenKey { key, force, .. } => {
+            warn_keypass_arg(&key, &logger);
+            let path = SecretKey::path(&settings, key.id.as_deref())?;
+            // Checked before the key pass is asked for.
+            SecretKey::check_replace(&path, force)?;
+            let stored = SecretKey::generate(&key.source()?, &path, force)?;
             logger.log(&format!(
-                "Generated secret key '{}' and saved at '{}'",
-                stored,
-                settings.storage_key_path.display()
+                "Generated {} secret key and saved at '{}'",
+                stored.kdf,
+                path.display()
             ));
+            if let (Some(salt), Some(params)) = (&stored.salt, &stored.params) {
+                logger.log(&format!(
+                    "Salt {} and params {} (pass them with --salt and --params to derive the \
+                     same key elsewhere)",
+                    hex_encode(salt),
+                    params
+                ));
+            }
+        }
+        Command::Lint { paths } => {
+            let report = lint(&paths, &settings);
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            if report.errors > 0 {
+                return Err(format!("lint found {} error(s)", report.errors).into());
+            }
         }
         Command::Pull { name, force } => {
             let mut repo = Repository::new(&name, &settings)?;
@@ -59,16 +200,105 @@ pub fn execute_command_line(args: Vec<String>) -> Result<(), Box<dyn Error>> {
         Command::Recipes { all } => {
             ListRecipes::list(all, &settings, &logger)?;
         }
+        Command::Rekey {
+            key,
+            paths,
+            dry_run,
+        } => {
+            warn_keypass_arg(&key, &logger);
+            let key_path = SecretKey::path(&settings, key.id.as_deref())?;
+            let old = load_crypto(&settings, &logger)?;
+            let stored = SecretKey::derive(&key.source()?)?;
+            let new = match &key.id {
+                Some(id) => Crypto::named(id, stored.key.clone()),
+                None => Crypto::new(stored.key.clone()),
+            }
+            .with_format(CipherFormat::parse(&settings.secret_format)?);
+            // The new key is saved before any recipe is encrypted with it.
+            let mut staged = None;
+            let summary = rekey(
+                &paths,
+                &settings,
+                &old,
+                &new,
+                key.id.as_deref(),
+                dry_run,
+                &mut || {
+                    staged = Some(SecretKey::stage(&stored, &key_path)?);
+                    Ok(())
+                },
+            );
+            let summary = match (summary, &staged) {
+                (Ok(summary), _) => summary,
+                (Err(err), Some(staged)) => {
+                    let message = format!("{}; the new key is kept at {}", err, staged.display());
+                    return Err(message.into());
+                }
+                (Err(err), None) => return Err(err),
+            };
+            for file in &summary.files {
+                let action = if dry_run { "Would rekey" } else { "Rekeyed" };
+                logger.log(&format!("{} {}", action, file));
+            }
+            for file in &summary.ignored {
+                logger.warn(&format!("Skipped {}: not a recipe", file));
+            }
+            logger.log(&format!(
+                "{} secret(s) in {} file(s), {} plain secret(s) and {} of other keys skipped",
+                summary.secrets,
+                summary.files.len(),
+                summary.skipped,
+                summary.other_keys
+            ));
+            if let Some(staged) = staged {
+                if let Some(backup) = SecretKey::install_staged(&staged, &key_path)? {
+                    logger.log(&format!("Previous key moved to '{}'", backup.display()));
+                }
+                logger.log(&format!(
+                    "Saved the new {} key at '{}'",
+                    stored.kdf,
+                    key_path.display()
+                ));
+                if key.id.is_none() && SecretKey::location(&settings) == KeyLocation::Env {
+                    logger.warn("BAKER_KEY still holds the previous key, replace it");
+                }
+            }
+        }
+        Command::Rewrap { paths, dry_run } => {
+            let crypto = load_crypto(&settings, &logger)?;
+            let summary = rewrap(&paths, &settings, &crypto, dry_run)?;
+            for file in &summary.files {
+                let action = if dry_run { "Would re-encrypt" } else { "Re-encrypted" };
+                logger.log(&format!("{} {}", action, file));
+            }
+            for file in &summary.ignored {
+                logger.warn(&format!("Skipped {}: not a recipe", file));
+            }
+            logger.log(&format!(
+                "{} recipient secret(s) in {} file(s), {} other secret(s) skipped",
+                summary.secrets,
+                summary.files.len(),
+                summary.skipped
+            ));
+        }
         Command::Rm { recipe_id } => {
             Repository::remove(&recipe_id, &settings, &logger)?;
         }
-        Command::Run { name, path, force } => {
+        Command::Run {
+            name,
+            path,
+            env,
+            force,
+        } => {
             logger.log("Baker start <:::> \n");
             let recipe_path = if let Some(name) = name {
                 let mut repo = Repository::new(&name, &settings)?;
                 repo.pull(force, &logger)?;
-                repo.local_path
-                    .ok_or("Repository pull did not set local path")?
+                let local_path = repo
+                    .local_path
+                    .ok_or("Repository pull did not set local path")?;
+                settings.recipe_format = Some(RecipeFormat::pulled(&settings)?.name().to_string());
+                local_path
             } else if let Some(path) = path {
                 path
             } else {
@@ -76,12 +306,27 @@ pub fn execute_command_line(args: Vec<String>) -> Result<(), Box<dyn Error>> {
             };
 
             let mut recipe = parse_recipe(&recipe_path, &settings, None)?;
-
-            if recipe.instructions.iter().any(|i| !i.secrets.is_empty()) {
-                let key = SecretKey::read(&settings)?;
-                let crypto = Crypto::new(key);
-                decrypt_secrets(&mut recipe.instructions, &crypto, recipe.case_sensitive)?;
+            if let Some(env) = &env {
+                recipe.select_env(env)?;
             }
+            recipe.resolve_env_variables()?;
+
+            let needs_key = recipe
+                .instructions
+                .iter()
+                .any(|i| i.secrets.values().any(|v| !provider::is_reference(v)));
+            let crypto = if needs_key {
+                Some(load_crypto(&settings, &logger)?)
+            } else {
+                None
+            };
+            decrypt_secrets(
+                &mut recipe.instructions,
+                crypto.as_ref(),
+                recipe.case_sensitive,
+                &Providers::from_settings(&settings),
+            )?;
+            interpolate_variables(&mut recipe.instructions, recipe.case_sensitive)?;
 
             for instruction in recipe.instructions.iter_mut() {
                 if instruction.is_remote {
@@ -103,3 +348,20 @@ pub fn execute_command_line(args: Vec<String>) -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+fn warn_keypass_arg(key: &KeyOptions, logger: &Logger) {
+    if matches!(key.keypass, SecretInput::Arg(_)) {
+        logger.warn(
+            "a key pass given as an argument is visible in shell history and process lists; \
+             use --keypass-stdin, --keypass-fd, --keypass-file or the prompt instead",
+        );
+    }
+}
+
+fn load_crypto(settings: &Settings, logger: &Logger) -> Result<Crypto, Box<dyn Error>> {
+    logger.debug(&format!(
+        "Reading the secret key from {}",
+        SecretKey::location(settings)
+    ));
+    Crypto::load(settings)
+}
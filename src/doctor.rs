@@ -0,0 +1,173 @@
+use crate::recipient::Identity;
+use crate::secret::{file_mode, key_permission_problem, KeyLocation, SecretKey, StoredKey};
+use crate::settings::Settings;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+pub struct Check {
+    pub level: &'static str,
+    pub message: String,
+}
+
+/// Reports problems with the secret keys and their directories without using them. Open
+/// permissions are errors, or only warnings with `STRICT_KEY_PERMISSIONS=false`.
+pub fn doctor(settings: &Settings) -> Vec<Check> {
+    let open = if settings.strict_key_permissions { "error" } else { "warn" };
+    let mut checks = Vec::new();
+    let mut push = |level, message: String| checks.push(Check { level, message });
+
+    if !settings.strict_key_permissions {
+        push(
+            "warn",
+            "STRICT_KEY_PERMISSIONS=false: key files readable by others are only warned about"
+                .to_string(),
+        );
+    }
+
+    let ring = key_ring(settings);
+    match SecretKey::location(settings) {
+        KeyLocation::Env => match env::var("BAKER_KEY").map(|v| StoredKey::parse(&v)) {
+            Ok(Ok(_)) => push("ok", "Default key is read from BAKER_KEY".to_string()),
+            _ => push("error", "BAKER_KEY does not hold a valid key".to_string()),
+        },
+        KeyLocation::File(path)
+            if !path.exists() && (!ring.is_empty() || settings.storage_identity.exists()) =>
+        {
+            push(
+                "ok",
+                format!(
+                    "No default key at '{}', only the key ring or identity is used",
+                    path.display()
+                ),
+            )
+        }
+        KeyLocation::File(path) => {
+            let (level, message) = key_file(&path, open);
+            push(level, message);
+        }
+    }
+
+    if let Some(dir) = settings.storage_key_path.parent() {
+        if let Some(check) = private_dir(dir) {
+            push(open, check);
+        }
+    }
+    if !ring.is_empty() {
+        if let Some(check) = private_dir(&settings.storage_keys) {
+            push(open, check);
+        }
+    }
+    for path in ring {
+        let (level, message) = key_file(&path, open);
+        push(level, message);
+    }
+    if settings.storage_identity.exists() {
+        let (level, message) = identity_file(&settings.storage_identity, open);
+        push(level, message);
+    }
+    checks
+}
+
+/// `open` is the level of a permission problem.
+fn key_file(path: &Path, open: &'static str) -> (&'static str, String) {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) => return ("error", format!("Cannot read key '{}': {}", path.display(), err)),
+    };
+    if let Some(problem) = key_permission_problem(path) {
+        return (open, problem);
+    }
+    match StoredKey::parse(&content) {
+        Ok(stored) => ("ok", format!("Key '{}' ({})", path.display(), stored.kdf)),
+        Err(err) => ("error", format!("Key '{}' is invalid: {}", path.display(), err)),
+    }
+}
+
+fn identity_file(path: &Path, open: &'static str) -> (&'static str, String) {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) => {
+            return ("error", format!("Cannot read identity '{}': {}", path.display(), err))
+        }
+    };
+    if let Some(problem) = key_permission_problem(path) {
+        return (open, problem);
+    }
+    match Identity::parse(&content) {
+        Ok(identity) => (
+            "ok",
+            format!("Identity '{}' ({})", path.display(), identity.recipient()),
+        ),
+        Err(err) => ("error", format!("Identity '{}' is invalid: {}", path.display(), err)),
+    }
+}
+
+fn private_dir(dir: &Path) -> Option<String> {
+    let mode = file_mode(dir)?;
+    if mode & 0o077 == 0 {
+        return None;
+    }
+    Some(format!(
+        "Directory '{}' has permissions {:04o}, expected 0700. Run 'chmod 700 {}'.",
+        dir.display(),
+        mode,
+        dir.display()
+    ))
+}
+
+fn key_ring(settings: &Settings) -> Vec<std::path::PathBuf> {
+    let mut keys: Vec<_> = fs::read_dir(&settings.storage_keys)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().and_then(|e| e.to_str()) == Some("key"))
+        .collect();
+    keys.sort();
+    keys
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::secret::KeySource;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn reports_open_key_files_and_directories() {
+        let dir = std::env::temp_dir().join("baker_rust_doctor_test");
+        let _ = fs::remove_dir_all(&dir);
+        let mut settings = Settings::load(false).unwrap();
+        settings.storage_key_path = dir.join("baker.key");
+        settings.storage_keys = dir.join("keys");
+        settings.storage_identity = dir.join("identity.key");
+        // The flag wins over BAKER_KEY, which other tests may set meanwhile.
+        settings.key_file = Some(settings.storage_key_path.clone());
+
+        let stored = SecretKey::derive(&KeySource::Random).unwrap();
+        SecretKey::save(&stored, &settings.storage_key_path, false).unwrap();
+        assert_eq!(file_mode(&settings.storage_key_path), Some(0o600));
+        assert_eq!(file_mode(&dir), Some(0o700));
+        assert!(SecretKey::read_path(&settings.storage_key_path, &settings).is_ok());
+        let levels: Vec<&str> = doctor(&settings).iter().map(|c| c.level).collect();
+        assert_eq!(levels, vec!["ok"]);
+
+        let open = fs::Permissions::from_mode(0o644);
+        fs::set_permissions(&settings.storage_key_path, open.clone()).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(SecretKey::read_path(&settings.storage_key_path, &settings).is_err());
+        let levels: Vec<&str> = doctor(&settings).iter().map(|c| c.level).collect();
+        assert_eq!(levels, vec!["error", "error"]);
+
+        settings.strict_key_permissions = false;
+        assert!(SecretKey::read_path(&settings.storage_key_path, &settings).is_ok());
+        let levels: Vec<&str> = doctor(&settings).iter().map(|c| c.level).collect();
+        assert_eq!(levels, vec!["warn", "warn", "warn"]);
+
+        // Replacing the open key writes a private file and tightens the existing directory.
+        SecretKey::save(&stored, &settings.storage_key_path, true).unwrap();
+        assert_eq!(file_mode(&settings.storage_key_path), Some(0o600));
+        assert_eq!(file_mode(&dir), Some(0o700));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    }
+}
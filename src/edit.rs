@@ -0,0 +1,198 @@
+use crate::logger::Logger;
+use crate::provider;
+use crate::recipe::{parse, rewrite_secret_content, EncryptionKeys, RecipeFormat};
+use crate::secret::Crypto;
+use crate::sensitive::SecretString;
+use crate::settings::Settings;
+use crate::storage::{create_private_dir, read_file, write_file, write_private};
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+/// Opens `path` in `$EDITOR` with its secrets decrypted in a private temp file and
+/// encrypts them again once the editor exits. Secrets whose plaintext did not change keep
+/// their previous ciphertext.
+pub fn edit_recipe(
+    path: &str,
+    settings: &Settings,
+    crypto: &Crypto,
+    logger: &Logger,
+) -> Result<(), Box<dyn Error>> {
+    let keys = EncryptionKeys::of(&parse(path, settings, Some(true))?, settings);
+    let format = RecipeFormat::for_file(Path::new(path), settings)?;
+    let original = read_file(path)?;
+
+    // (instruction, key) -> (ciphertext, plaintext)
+    let mut previous: HashMap<(String, String), (String, SecretString)> = HashMap::new();
+    let decrypted = rewrite_secret_content(&original, format, &mut |name, key, value| {
+        if !Crypto::is_ciphertext(value) {
+            return Ok(None);
+        }
+        let plain = crypto.decrypt_secret(value, name, key)?;
+        let text = plain.to_string();
+        previous.insert((name.to_string(), key.to_string()), (value.to_string(), plain));
+        Ok(Some(text))
+    })?
+    .map(SecretString::new)
+    .unwrap_or_else(|| original.as_str().into());
+
+    let dir = env::temp_dir().join(format!("baker-edit-{}", process::id()));
+    let file_name = Path::new(path)
+        .file_name()
+        .ok_or("edit expects a recipe file path")?;
+    let temp = dir.join(file_name);
+    if let Err(err) =
+        create_private_dir(&dir).and_then(|_| write_private(&temp, decrypted.as_bytes()))
+    {
+        wipe(&dir, &temp);
+        return Err(err.into());
+    }
+
+    // The plaintext copy is only wiped once the edits are encrypted. A rejected edit reopens
+    // the editor; leaving it without further changes gives up and keeps the file.
+    let mut rejected: Option<SecretString> = None;
+    let saved = loop {
+        let edited = open_editor(&temp).and_then(|_| Ok(read_file(&temp.to_string_lossy())?));
+        let edited = match edited {
+            Ok(edited) => SecretString::new(edited),
+            Err(err) => break Err(err),
+        };
+        if edited == decrypted {
+            break Ok(None);
+        }
+        match encrypt_edits(&edited, format, &previous, &keys, crypto) {
+            Ok(saved) => break Ok(Some(saved)),
+            Err(err) if rejected.as_ref() != Some(&edited) => {
+                logger.warn(&format!(
+                    "{}. Fix it in the editor, or leave it unchanged to stop",
+                    err
+                ));
+                rejected = Some(edited);
+            }
+            Err(err) => {
+                let message = format!("{}. Your edits are kept in {}", err, temp.display());
+                return Err(message.into());
+            }
+        }
+    };
+    wipe(&dir, &temp);
+
+    let (output, encrypted, kept) = match saved? {
+        Some(saved) => saved,
+        None => {
+            logger.log("No changes, recipe left untouched");
+            return Ok(());
+        }
+    };
+    write_file(path, &output)?;
+    logger.log(&format!(
+        "Recipe saved: {} secret(s) encrypted, {} unchanged",
+        encrypted, kept
+    ));
+    // Report problems introduced while editing; secrets are already encrypted on disk.
+    parse(path, settings, Some(true))?;
+    Ok(())
+}
+
+/// Encrypts the secrets of the edited recipe; returns it with the number of secrets
+/// encrypted and kept. Unchanged plaintexts keep their previous ciphertext.
+fn encrypt_edits(
+    edited: &str,
+    format: RecipeFormat,
+    previous: &HashMap<(String, String), (String, SecretString)>,
+    keys: &EncryptionKeys,
+    crypto: &Crypto,
+) -> Result<(String, usize, usize), Box<dyn Error>> {
+    let mut encrypted = 0;
+    let mut kept = 0;
+    let output = rewrite_secret_content(edited, format, &mut |name, key, value| {
+        if let Some((cipher, plain)) = previous.get(&(name.to_string(), key.to_string())) {
+            if plain.as_str() == value {
+                kept += 1;
+                return Ok(Some(cipher.clone()));
+            }
+        }
+        if Crypto::is_ciphertext(value) || provider::is_reference(value) {
+            return Ok(None);
+        }
+        encrypted += 1;
+        let target = keys.for_instruction(name);
+        Ok(Some(crypto.encrypt_secret(target, value, name, key)?))
+    })?
+    .unwrap_or_else(|| edited.to_string());
+    Ok((output, encrypted, kept))
+}
+
+fn open_editor(file: &Path) -> Result<(), Box<dyn Error>> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let mut parts = editor.split_whitespace();
+    let program = parts.next().ok_or("EDITOR is empty")?;
+    let status = Command::new(program)
+        .args(parts)
+        .arg(file)
+        .status()
+        .map_err(|e| format!("Cannot start editor '{}': {}", editor, e))?;
+    if !status.success() {
+        return Err(format!("Editor '{}' exited with {}", editor, status).into());
+    }
+    Ok(())
+}
+
+/// Overwrites the plaintext copy before removing it together with any editor leftovers.
+fn wipe(dir: &PathBuf, file: &Path) {
+    if let Ok(meta) = fs::metadata(file) {
+        let _ = fs::write(file, vec![0u8; meta.len() as usize]);
+    }
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recipe::{decrypt_recipe_file, encrypt_recipe_file};
+
+    #[test]
+    fn edit_keeps_ciphertext_of_unchanged_secrets() {
+        let dir = env::temp_dir().join("baker_rust_edit_test");
+        let path = dir.join("edit.cfg");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            &path,
+            "[app:template]\ntemplate=t\n[app:secrets]\nKEEP=same\nCHANGE=old\n",
+        )
+        .unwrap();
+        let path = path.to_string_lossy().to_string();
+
+        let settings = Settings::load(false).unwrap();
+        let crypto = Crypto::new(vec![4u8; 32]);
+        encrypt_recipe_file(&path, &settings, &crypto).unwrap();
+        let before = read_file(&path).unwrap();
+        let keep = before.lines().find(|l| l.starts_with("KEEP")).unwrap().to_string();
+
+        env::set_var("VISUAL", "sed -i s/=.old/=new/");
+        edit_recipe(&path, &settings, &crypto, &Logger::new(false)).unwrap();
+
+        let after = read_file(&path).unwrap();
+        assert!(after.contains(&keep));
+        assert!(!after.contains("new"));
+        let secrets = decrypt_recipe_file(&path, &settings, &crypto).unwrap();
+        assert!(secrets.contains(&("app".into(), "KEEP".into(), "same".into())));
+        assert!(secrets.contains(&("app".into(), "CHANGE".into(), "new".into())));
+
+        // A broken edit reopens the editor; unchanged there, it stops and keeps the edits.
+        env::set_var("VISUAL", "sed -i s/^.app:secrets./[a:b:c:d]/");
+        let err = edit_recipe(&path, &settings, &crypto, &Logger::new(false)).unwrap_err();
+        let kept = env::temp_dir()
+            .join(format!("baker-edit-{}", process::id()))
+            .join("edit.cfg");
+        assert!(err.to_string().contains(&kept.display().to_string()), "{}", err);
+        assert!(read_file(&kept.to_string_lossy()).unwrap().contains("[a:b:c:d]\nKEEP = same"));
+        assert_eq!(read_file(&path).unwrap(), after);
+        wipe(&kept.parent().unwrap().to_path_buf(), &kept);
+    }
+}
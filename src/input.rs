@@ -0,0 +1,116 @@
+use crate::sensitive::SecretString;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read};
+
+/// Where a sensitive value (key pass or plaintext secret) is read from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SecretInput {
+    /// Given on the command line, visible in shell history and `ps`.
+    Arg(SecretString),
+    /// Typed at the terminal without echo.
+    Prompt,
+    Stdin,
+    Fd(u32),
+    File(String),
+}
+
+impl SecretInput {
+    /// Takes `--<name>-stdin`, `--<name>-fd N` and `--<name>-file PATH` out of `args`.
+    pub fn take(args: &mut Vec<String>, name: &str) -> Result<Option<Self>, Box<dyn Error>> {
+        let mut input = None;
+        let mut idx = 0;
+        while idx < args.len() {
+            let suffix = args[idx]
+                .strip_prefix("--")
+                .and_then(|f| f.strip_prefix(name))
+                .map(str::to_string);
+            let found = match suffix.as_deref() {
+                Some("-stdin") => {
+                    args.remove(idx);
+                    SecretInput::Stdin
+                }
+                Some(kind @ ("-fd" | "-file")) => {
+                    let flag = args.remove(idx);
+                    if idx >= args.len() {
+                        return Err(format!("{} expects a value", flag).into());
+                    }
+                    let value = args.remove(idx);
+                    if kind == "-fd" {
+                        let fd = value
+                            .parse()
+                            .map_err(|_| format!("{} expects a file descriptor number", flag))?;
+                        SecretInput::Fd(fd)
+                    } else {
+                        SecretInput::File(value)
+                    }
+                }
+                _ => {
+                    idx += 1;
+                    continue;
+                }
+            };
+            if input.replace(found).is_some() {
+                return Err(format!("Only one --{}-* input can be given", name).into());
+            }
+        }
+        Ok(input)
+    }
+
+    /// Reads the value; one trailing line break is dropped. `confirm` asks twice at the prompt.
+    pub fn read(&self, label: &str, confirm: bool) -> Result<SecretString, Box<dyn Error>> {
+        let raw = SecretString::new(match self {
+            SecretInput::Arg(value) => return Ok(value.clone()),
+            SecretInput::Prompt => {
+                let prompt = |text: String| {
+                    rpassword::prompt_password(text).map_err(|e| {
+                        let label = label.to_lowercase();
+                        format!("Cannot prompt for {} without a terminal: {}", label, e)
+                    })
+                };
+                let value = SecretString::new(prompt(format!("{}: ", label))?);
+                if confirm && SecretString::new(prompt(format!("Confirm {}: ", label))?) != value {
+                    return Err(format!("{} entries do not match", label).into());
+                }
+                return Ok(value);
+            }
+            SecretInput::Stdin => {
+                let mut value = String::new();
+                io::stdin().read_to_string(&mut value)?;
+                value
+            }
+            SecretInput::Fd(fd) => fs::read_to_string(format!("/dev/fd/{}", fd))
+                .map_err(|e| format!("Cannot read {} from fd {}: {}", label, fd, e))?,
+            SecretInput::File(path) => fs::read_to_string(path)
+                .map_err(|e| format!("Cannot read {} from '{}': {}", label, path, e))?,
+        });
+        let value = raw
+            .strip_suffix('\n')
+            .map(|v| v.strip_suffix('\r').unwrap_or(v))
+            .unwrap_or(&raw);
+        Ok(value.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_input_flags_and_reads_files() {
+        let path = std::env::temp_dir().join("baker_rust_input_test");
+        fs::write(&path, "pass phrase\n\n").unwrap();
+        let mut args: Vec<String> = ["--keypass-file", path.to_str().unwrap(), "x"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        let input = SecretInput::take(&mut args, "keypass").unwrap().unwrap();
+        assert_eq!(args, vec!["x"]);
+        assert_eq!(input.read("key pass", false).unwrap(), "pass phrase\n");
+
+        let mut args = vec!["--keypass-fd".to_string(), "nope".to_string()];
+        assert!(SecretInput::take(&mut args, "keypass").is_err());
+        let mut args = vec!["--keypass-stdin".to_string(), "--keypass-fd".to_string(), "3".into()];
+        assert!(SecretInput::take(&mut args, "keypass").is_err());
+    }
+}
@@ -0,0 +1,298 @@
+use crate::provider;
+use crate::recipe::{interpolation_refs, parse, Instruction, Recipe, RecipeError, SharedValues};
+use crate::secret::Crypto;
+use crate::settings::Settings;
+use crate::storage::read_file;
+use crate::template::BakerTemplate;
+use crate::utils::is_url;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Serialize)]
+pub struct Finding {
+    pub recipe: String,
+    pub severity: &'static str,
+    pub check: &'static str,
+    pub instruction: Option<String>,
+    pub key: Option<String>,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct LintReport {
+    pub errors: usize,
+    pub warnings: usize,
+    pub findings: Vec<Finding>,
+}
+
+impl LintReport {
+    fn push(&mut self, finding: Finding) {
+        match finding.severity {
+            "error" => self.errors += 1,
+            _ => self.warnings += 1,
+        }
+        self.findings.push(finding);
+    }
+}
+
+/// Checks recipes statically: nothing is rendered and no secret key is needed.
+pub fn lint(paths: &[String], settings: &Settings) -> LintReport {
+    let mut report = LintReport::default();
+    for path in paths {
+        match parse(path, settings, None) {
+            Ok(recipe) => lint_recipe(path, &recipe, settings, &mut report),
+            Err(err) => {
+                let line = err.downcast_ref::<RecipeError>().map(|e| e.line);
+                report.push(Finding {
+                    recipe: path.clone(),
+                    severity: "error",
+                    check: "parse",
+                    instruction: None,
+                    key: None,
+                    line,
+                    message: err.to_string(),
+                });
+            }
+        }
+    }
+    report
+}
+
+fn lint_recipe(path: &str, recipe: &Recipe, settings: &Settings, report: &mut LintReport) {
+    let finding = |severity, check, instruction: Option<&str>, key: Option<&str>, message| Finding {
+        recipe: path.to_string(),
+        severity,
+        check,
+        instruction: instruction.map(str::to_string),
+        key: key.map(str::to_string),
+        line: None,
+        message,
+    };
+
+    // `None` means the references are unknown (remote or unreadable template).
+    let mut references: Vec<Option<HashSet<String>>> = Vec::new();
+
+    for instruction in &recipe.instructions {
+        let name = Some(instruction.name.as_str());
+        let template = &instruction.template;
+
+        if let Some(mode) = &template.mode {
+            if !matches!(u32::from_str_radix(mode, 8), Ok(value) if value <= 0o7777) {
+                report.push(finding(
+                    "error",
+                    "mode-invalid",
+                    name,
+                    None,
+                    format!("Mode '{}' is not a valid octal permission", mode),
+                ));
+            }
+        }
+
+        let mut used: Option<HashSet<String>> = None;
+        if !is_url(&template.template) {
+            match read_file(&template.template) {
+                Ok(source) => {
+                    let tpl = BakerTemplate::new(&source, settings.recipe_case_sensitive);
+                    match tpl.variables() {
+                        Ok(names) => {
+                            for var in &names {
+                                if !instruction.variables.contains_key(var)
+                                    && !instruction.secrets.contains_key(var)
+                                {
+                                    report.push(finding(
+                                        "error",
+                                        "variable-missing",
+                                        name,
+                                        Some(var),
+                                        format!(
+                                            "Template '{}' uses '{}' which is not defined",
+                                            template.template, var
+                                        ),
+                                    ));
+                                }
+                            }
+                            used = Some(names.into_iter().collect());
+                        }
+                        Err(err) => report.push(finding(
+                            "error",
+                            "template-invalid",
+                            name,
+                            None,
+                            format!("Template '{}': {}", template.template, err),
+                        )),
+                    }
+                }
+                Err(_) => report.push(finding(
+                    "error",
+                    "template-missing",
+                    name,
+                    None,
+                    format!("Template '{}' does not exist", template.template),
+                )),
+            }
+        }
+        if let Some(used) = used.as_mut() {
+            for value in instruction.variables.values() {
+                used.extend(interpolation_refs(value, recipe.case_sensitive));
+            }
+        }
+
+        let shared = applicable_shared(&recipe.shared, instruction);
+        let own = |key: &String, value: &str, secret: bool| {
+            !shared.iter().any(|s| {
+                let values = if secret { &s.secrets } else { &s.variables };
+                values.get(key).map(String::as_str) == Some(value)
+            })
+        };
+        for (key, value) in sorted(&instruction.variables) {
+            if own(key, value.as_str(), false) && used.as_ref().is_some_and(|u| !u.contains(key)) {
+                report.push(finding(
+                    "warning",
+                    "variable-unused",
+                    name,
+                    Some(key),
+                    format!("Variable '{}' is not used by the template", key),
+                ));
+            }
+        }
+        for (key, value) in sorted(&instruction.secrets) {
+            if !own(key, value, true) {
+                continue;
+            }
+            if used.as_ref().is_some_and(|u| !u.contains(key)) {
+                report.push(finding(
+                    "warning",
+                    "secret-unused",
+                    name,
+                    Some(key),
+                    format!("Secret '{}' is not used by the template", key),
+                ));
+            }
+            if !Crypto::is_ciphertext(value) && !provider::is_reference(value) {
+                report.push(finding(
+                    "error",
+                    "secret-unencrypted",
+                    name,
+                    Some(key),
+                    format!("Secret '{}' is not encrypted", key),
+                ));
+            }
+        }
+        references.push(used);
+    }
+
+    for shared in &recipe.shared {
+        let section = match &shared.env {
+            Some(env) => format!("{}:*", env),
+            None => "*".to_string(),
+        };
+        let users: Vec<&Option<HashSet<String>>> = recipe
+            .instructions
+            .iter()
+            .zip(&references)
+            .filter(|(instruction, _)| applies_to(shared, instruction))
+            .map(|(_, used)| used)
+            .collect();
+        let is_used = |key: &String| {
+            users.iter().any(|used| match used {
+                Some(used) => used.contains(key),
+                None => true,
+            })
+        };
+        for (key, _) in sorted(&shared.variables) {
+            if !is_used(key) {
+                report.push(finding(
+                    "warning",
+                    "variable-unused",
+                    Some(&section),
+                    Some(key),
+                    format!("Shared variable '{}' is not used by any template", key),
+                ));
+            }
+        }
+        for (key, value) in sorted(&shared.secrets) {
+            if !is_used(key) {
+                report.push(finding(
+                    "warning",
+                    "secret-unused",
+                    Some(&section),
+                    Some(key),
+                    format!("Shared secret '{}' is not used by any template", key),
+                ));
+            }
+            if !Crypto::is_ciphertext(value) && !provider::is_reference(value) {
+                report.push(finding(
+                    "error",
+                    "secret-unencrypted",
+                    Some(&section),
+                    Some(key),
+                    format!("Secret '{}' is not encrypted", key),
+                ));
+            }
+        }
+    }
+}
+
+/// Map entries by key, so reports are stable between runs.
+fn sorted<V>(map: &HashMap<String, V>) -> Vec<(&String, &V)> {
+    let mut entries: Vec<(&String, &V)> = map.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    entries
+}
+
+fn applies_to(shared: &SharedValues, instruction: &Instruction) -> bool {
+    shared.env.is_none() || shared.env == instruction.env
+}
+
+fn applicable_shared<'a>(
+    shared: &'a [SharedValues],
+    instruction: &Instruction,
+) -> Vec<&'a SharedValues> {
+    shared.iter().filter(|s| applies_to(s, instruction)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn reports_template_variable_and_secret_problems() {
+        let dir = std::env::temp_dir().join("baker_rust_lint_test");
+        fs::create_dir_all(&dir).unwrap();
+        let template = dir.join("app.tpl");
+        fs::write(&template, "{{ HOST }} {{ PASSWORD }} {{ MISSING }}").unwrap();
+        let recipe = dir.join("lint.cfg");
+        fs::write(
+            &recipe,
+            format!(
+                "[dev:*:variables]\nUNUSED_SHARED=1\n\
+                 [dev:app:template]\ntemplate={}\nmode=0999\n\
+                 [dev:app:variables]\nHOST=${{PORT}}\nPORT=80\nEXTRA=x\n\
+                 [dev:app:secrets]\nPASSWORD=plain\n\
+                 [prod:gone:template]\ntemplate={}\n",
+                template.display(),
+                dir.join("missing.tpl").display()
+            ),
+        )
+        .unwrap();
+
+        let settings = Settings::load(false).unwrap();
+        let report = lint(&[recipe.to_string_lossy().to_string()], &settings);
+        let checks: Vec<(&str, Option<&str>)> = report
+            .findings
+            .iter()
+            .map(|f| (f.check, f.key.as_deref()))
+            .collect();
+        assert!(checks.contains(&("mode-invalid", None)));
+        assert!(checks.contains(&("variable-missing", Some("missing"))));
+        assert!(checks.contains(&("variable-unused", Some("extra"))));
+        assert!(!checks.contains(&("variable-unused", Some("port"))));
+        assert!(checks.contains(&("variable-unused", Some("unused_shared"))));
+        assert!(checks.contains(&("secret-unencrypted", Some("password"))));
+        assert!(checks.contains(&("template-missing", None)));
+        assert_eq!(report.errors, 4);
+        assert_eq!(report.warnings, 2);
+    }
+}
@@ -12,6 +12,10 @@ impl Logger {
         println!("{}", message);
     }
 
+    pub fn warn(&self, message: &str) {
+        eprintln!("Warning: {}", message);
+    }
+
     pub fn debug(&self, message: &str) {
         if self.debug {
             eprintln!("DEBUG: {}", message);
@@ -1,9 +1,17 @@
 mod cli;
 mod commands;
+mod doctor;
+mod edit;
+mod input;
+mod lint;
 mod logger;
+mod provider;
 mod recipe;
+mod recipient;
+mod rekey;
 mod repository;
 mod secret;
+mod sensitive;
 mod settings;
 mod storage;
 mod template;
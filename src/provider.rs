@@ -0,0 +1,173 @@
+use crate::recipe::env_reference;
+use crate::sensitive::SecretString;
+use crate::settings::Settings;
+use std::error::Error;
+use std::fs;
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const PROVIDERS: [&str; 3] = ["file:", "cmd:", "env:"];
+
+/// Whether a secret is a provider reference instead of ciphertext.
+pub fn is_reference(value: &str) -> bool {
+    PROVIDERS.iter().any(|p| value.starts_with(p))
+}
+
+/// The providers secrets may use (`SECRET_PROVIDERS`) and how long commands may run. Only
+/// `env` is on by default: `file:` and `cmd:` would let any pulled recipe read files or run
+/// commands on the host.
+#[derive(Debug, Clone)]
+pub struct Providers {
+    pub enabled: Vec<String>,
+    pub command_timeout: Duration,
+}
+
+impl Providers {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Providers {
+            enabled: settings.secret_providers.clone(),
+            command_timeout: Duration::from_secs(settings.secret_command_timeout),
+        }
+    }
+}
+
+/// Resolves `file:PATH`, `cmd:COMMAND` and `env:NAME[:-default]` secrets; `None` for other
+/// values. Errors name the provider and never contain the resolved value.
+pub fn resolve(
+    value: &str,
+    providers: &Providers,
+) -> Result<Option<SecretString>, Box<dyn Error>> {
+    if let Some((name, _)) = value.split_once(':') {
+        if is_reference(value) && !providers.enabled.iter().any(|p| p == name) {
+            return Err(format!(
+                "{} provider is disabled, add '{}' to SECRET_PROVIDERS in .bakerc to allow it",
+                name, name
+            )
+            .into());
+        }
+    }
+    if let Some(path) = value.strip_prefix("file:") {
+        let path = path.trim();
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("file provider: cannot read '{}': {}", path, e))?;
+        return Ok(Some(trim_line_break(content).into()));
+    }
+    if let Some(command) = value.strip_prefix("cmd:") {
+        return run_command(command.trim(), providers.command_timeout)
+            .map(|output| Some(output.into()))
+            .map_err(|e| format!("cmd provider '{}': {}", command.trim(), e).into());
+    }
+    if value.starts_with("env:") {
+        return env_reference(value)
+            .map(|resolved| resolved.map(SecretString::new))
+            .map_err(|e| format!("env provider: {}", e).into());
+    }
+    Ok(None)
+}
+
+/// Runs `command` through the shell and returns its stdout, killing it after `timeout`.
+fn run_command(command: &str, timeout: Duration) -> Result<String, String> {
+    if command.is_empty() {
+        return Err("missing command".to_string());
+    }
+    let (shell, flag) = if cfg!(windows) { ("cmd", "/C") } else { ("sh", "-c") };
+    let mut child = Command::new(shell)
+        .args([flag, command])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("cannot start: {}", e))?;
+
+    // Read both pipes while waiting so a chatty command cannot block on a full pipe.
+    let mut stdout = child.stdout.take().expect("piped stdout");
+    let mut stderr = child.stderr.take().expect("piped stderr");
+    let out = thread::spawn(move || {
+        let mut buf = Vec::new();
+        stdout.read_to_end(&mut buf).map(|_| buf)
+    });
+    let err = thread::spawn(move || {
+        let mut buf = String::new();
+        let _ = stderr.read_to_string(&mut buf);
+        buf
+    });
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+            break status;
+        }
+        if started.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("timed out after {:?}", timeout));
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    let stdout = out
+        .join()
+        .map_err(|_| "cannot read output".to_string())?
+        .map_err(|e| format!("cannot read output: {}", e))?;
+    let stderr = err.join().unwrap_or_default();
+    if !status.success() {
+        // Only stderr is shown: stdout may hold (part of) the secret.
+        let detail = stderr.lines().find(|l| !l.trim().is_empty()).unwrap_or("").trim();
+        return Err(match detail {
+            "" => format!("exited with {}", status),
+            detail => format!("exited with {}: {}", status, detail),
+        });
+    }
+    let stdout = String::from_utf8(stdout).map_err(|_| "output is not valid UTF-8".to_string())?;
+    Ok(trim_line_break(stdout))
+}
+
+fn trim_line_break(mut value: String) -> String {
+    if value.ends_with('\n') {
+        value.pop();
+        if value.ends_with('\r') {
+            value.pop();
+        }
+    }
+    value
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_providers_without_leaking_values() {
+        let timeout = Duration::from_secs(5);
+        let only_env = Providers {
+            enabled: vec!["env".into()],
+            command_timeout: timeout,
+        };
+        let err = resolve("cmd:touch /tmp/baker_rust_provider_pwned", &only_env).unwrap_err();
+        assert!(err.to_string().starts_with("cmd provider is disabled"), "{}", err);
+        assert!(resolve("file:/etc/hostname", &only_env).is_err());
+        assert_eq!(resolve("env:BAKER_RUST_UNSET:-dflt", &only_env).unwrap().unwrap(), "dflt");
+
+        let all = |timeout| Providers {
+            enabled: vec!["env".into(), "file".into(), "cmd".into()],
+            command_timeout: timeout,
+        };
+        let providers = all(timeout);
+        let path = std::env::temp_dir().join("baker_rust_provider_test");
+        fs::write(&path, "from-file\n").unwrap();
+        let file = format!("file:{}", path.display());
+        assert_eq!(resolve(&file, &providers).unwrap().unwrap(), "from-file");
+        assert_eq!(resolve("cmd:printf 'a b\\n'", &providers).unwrap().unwrap(), "a b");
+        assert_eq!(resolve("env:BAKER_RUST_UNSET:-dflt", &providers).unwrap().unwrap(), "dflt");
+        assert_eq!(resolve("abc\\def", &providers).unwrap(), None);
+
+        let err = resolve("cmd:echo hunter2; exit 3", &providers).unwrap_err().to_string();
+        assert!(err.starts_with("cmd provider 'echo hunter2; exit 3': exited with"));
+        assert!(!err.replace("echo hunter2", "").contains("hunter2"));
+        let err = resolve("cmd:sleep 5", &all(Duration::from_millis(100))).unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+        let err = resolve("file:/nonexistent/secret", &providers).unwrap_err();
+        assert!(err.to_string().starts_with("file provider: cannot read"));
+    }
+}
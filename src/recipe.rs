@@ -1,9 +1,17 @@
-use crate::secret::Crypto;
-use crate::settings::Settings;
+use crate::provider::{self, Providers};
+use crate::secret::{Crypto, EncryptTarget};
+use crate::sensitive::SecretString;
+use crate::settings::{split_list, Settings};
 use crate::storage::{read_file, write_file};
 use crate::utils::is_url;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::env;
 use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde_json::{Map as JsonMap, Value as JsonValue};
+use toml_edit::{DocumentMut, ImDocument, Item, TableLike, Value as TomlValue};
 
 #[derive(Debug, Clone)]
 pub struct TemplateInfo {
@@ -12,78 +20,815 @@ pub struct TemplateInfo {
     pub user: Option<String>,
     pub group: Option<String>,
     pub mode: Option<String>,
+    pub after: Vec<String>,
+    /// Key ring id used to encrypt this instruction's secrets.
+    pub key: Option<String>,
+    /// Public keys this instruction's secrets are encrypted to, instead of a key.
+    pub recipients: Vec<String>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Instruction {
     pub name: String,
+    pub env: Option<String>,
     pub template: TemplateInfo,
-    pub variables: HashMap<String, String>,
+    /// Variables and, once decrypted, secrets; wiped from memory when dropped.
+    pub variables: HashMap<String, SecretString>,
     pub secrets: HashMap<String, String>,
+    /// Secrets merged from a shared section, with that section (`*` or `<env>:*`): bound
+    /// ciphertext of these is bound to it instead of to the instruction.
+    pub shared_secrets: HashMap<String, String>,
+    /// Variables whose value is not recipe text (decrypted secrets, `env:` values): inserted
+    /// as they are by `interpolate_variables`, never scanned for `${NAME}`.
+    pub verbatim: HashSet<String>,
     pub is_remote: bool,
 }
 
+/// A recipe problem tied to the file and line that caused it.
+#[derive(Debug, Clone)]
+pub struct RecipeError {
+    pub path: String,
+    pub line: usize,
+    pub text: String,
+    pub message: String,
+}
+
+impl RecipeError {
+    fn new(path: &Path, line: usize, text: &str, message: String) -> Self {
+        Self {
+            path: path.display().to_string(),
+            line,
+            text: text.to_string(),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for RecipeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.path, self.line, self.message)
+    }
+}
+
+impl Error for RecipeError {}
+
+/// Syntax of a recipe file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecipeFormat {
+    Ini,
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl RecipeFormat {
+    pub fn from_name(name: &str) -> Result<Self, Box<dyn Error>> {
+        match name.to_lowercase().as_str() {
+            "ini" | "cfg" => Ok(RecipeFormat::Ini),
+            "toml" => Ok(RecipeFormat::Toml),
+            "yaml" | "yml" => Ok(RecipeFormat::Yaml),
+            "json" => Ok(RecipeFormat::Json),
+            other => Err(format!(
+                "Unsupported recipe format '{}'. Use ini, toml, yaml or json",
+                other
+            )
+            .into()),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RecipeFormat::Ini => "ini",
+            RecipeFormat::Toml => "toml",
+            RecipeFormat::Yaml => "yaml",
+            RecipeFormat::Json => "json",
+        }
+    }
+
+    /// Picks the format from the file extension, defaulting to INI.
+    pub fn detect(path: &Path) -> Self {
+        RecipeFormat::from_extension(path.extension().and_then(|e| e.to_str()).unwrap_or(""))
+    }
+
+    fn from_extension(ext: &str) -> Self {
+        match ext.to_lowercase().as_str() {
+            "toml" => RecipeFormat::Toml,
+            "yaml" | "yml" => RecipeFormat::Yaml,
+            "json" => RecipeFormat::Json,
+            _ => RecipeFormat::Ini,
+        }
+    }
+
+    /// Format of a recipe pulled from the repository. Its local file is named after the URL
+    /// (`name.yaml?at=v1`, or only the version with some custom patterns), so unless
+    /// `RECIPE_FORMAT` is set it comes from `RECIPE_EXT`.
+    pub fn pulled(settings: &Settings) -> Result<Self, Box<dyn Error>> {
+        Ok(RecipeFormat::from_settings(settings)?
+            .unwrap_or_else(|| RecipeFormat::from_extension(&settings.recipe_ext)))
+    }
+
+    fn from_settings(settings: &Settings) -> Result<Option<Self>, Box<dyn Error>> {
+        settings
+            .recipe_format
+            .as_deref()
+            .map(RecipeFormat::from_name)
+            .transpose()
+    }
+
+    pub fn for_file(path: &Path, settings: &Settings) -> Result<Self, Box<dyn Error>> {
+        Ok(RecipeFormat::from_settings(settings)?.unwrap_or_else(|| RecipeFormat::detect(path)))
+    }
+}
+
+/// Values from `[variables]`/`[secrets]` (no env) or `[<env>:*:variables]`/`[<env>:*:secrets]`.
+#[derive(Debug, Clone, Default)]
+pub struct SharedValues {
+    pub env: Option<String>,
+    pub variables: HashMap<String, String>,
+    pub secrets: HashMap<String, String>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Recipe {
     pub instructions: Vec<Instruction>,
+    pub shared: Vec<SharedValues>,
     pub case_sensitive: bool,
     pub raw_lines: Vec<String>,
+    /// Key ring id declared with a top-level `key = <id>` in the recipe file itself.
+    pub key: Option<String>,
+    /// Recipients declared with a top-level `recipients = age1..., ...`.
+    pub recipients: Vec<String>,
 }
 
-pub fn parse(file: &str, settings: &Settings, case_override: Option<bool>) -> Result<Recipe, Box<dyn Error>> {
-    let content = read_file(file)?;
-    let mut current_section: Option<String> = None;
-    let mut raw_lines: Vec<String> = Vec::new();
-    let mut partial: HashMap<String, PartialInstruction> = HashMap::new();
-    let case_sensitive = case_override.unwrap_or(settings.recipe_case_sensitive);
+impl Recipe {
+    /// Environments declared through `[<env>:<instruction>:<part>]` sections, sorted.
+    pub fn environments(&self) -> Vec<String> {
+        let mut envs: Vec<String> = self
+            .instructions
+            .iter()
+            .filter_map(|i| i.env.clone())
+            .collect();
+        envs.sort();
+        envs.dedup();
+        envs
+    }
 
-    for line in content.lines() {
-        raw_lines.push(line.to_string());
-        let trimmed = line.trim();
-        if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with(';') {
-            continue;
-        }
-        if trimmed.starts_with('[') && trimmed.ends_with(']') {
-            let name = trimmed.trim_matches(|c| c == '[' || c == ']').to_string();
-            current_section = Some(name);
-            continue;
+    /// Keeps only instructions of `env` plus the ones not bound to any environment.
+    pub fn select_env(&mut self, env: &str) -> Result<(), Box<dyn Error>> {
+        let envs = self.environments();
+        if !envs.iter().any(|e| e == env) {
+            let available = if envs.is_empty() {
+                "none".to_string()
+            } else {
+                envs.join(", ")
+            };
+            return Err(format!(
+                "Environment '{}' not found in recipe. Available environments: {}",
+                env, available
+            )
+            .into());
         }
-        if let Some((key, value)) = trimmed.split_once('=') {
-            let key = key.trim().to_string();
-            let value = value.trim().to_string();
-            if let Some(section) = &current_section {
-                let (inst_name, part) = split_section(section)?;
-                let entry = partial.entry(inst_name.to_string()).or_default();
-                entry.add_value(
-                    part,
-                    &key,
-                    &value,
-                    case_sensitive,
-                )?;
+        self.instructions.retain(|i| match &i.env {
+            Some(e) => e == env,
+            None => true,
+        });
+        Ok(())
+    }
+
+    /// Replaces variables written as `env:NAME` or `env:NAME:-default` with the value of
+    /// the process environment variable. The default applies when it is unset or empty.
+    pub fn resolve_env_variables(&mut self) -> Result<(), Box<dyn Error>> {
+        for instruction in self.instructions.iter_mut() {
+            for (key, value) in instruction.variables.iter_mut() {
+                if let Some(resolved) = env_reference(value)
+                    .map_err(|e| format!("{} for [{}] {}", e, instruction.name, key))?
+                {
+                    *value = resolved.into();
+                    instruction.verbatim.insert(key.clone());
+                }
             }
         }
+        Ok(())
+    }
+}
+
+/// Resolves `env:NAME` and `env:NAME:-default`; `None` for other values.
+pub fn env_reference(value: &str) -> Result<Option<String>, String> {
+    let spec = match value.strip_prefix("env:") {
+        Some(spec) => spec,
+        None => return Ok(None),
+    };
+    let (name, default) = match spec.split_once(":-") {
+        Some((name, default)) => (name.trim(), Some(default)),
+        None => (spec.trim(), None),
+    };
+    if name.is_empty() {
+        return Err("Missing environment variable name after 'env:'".to_string());
     }
+    match (env::var(name), default) {
+        (Ok(found), Some(default)) if found.is_empty() => Ok(Some(default.to_string())),
+        (Ok(found), _) => Ok(Some(found)),
+        (Err(_), Some(default)) => Ok(Some(default.to_string())),
+        (Err(_), None) => Err(format!("Environment variable '{}' is not set", name)),
+    }
+}
+
+pub fn parse(file: &str, settings: &Settings, case_override: Option<bool>) -> Result<Recipe, Box<dyn Error>> {
+    let case_sensitive = case_override.unwrap_or(settings.recipe_case_sensitive);
+    let mut loader = RecipeLoader {
+        partial: Vec::new(),
+        positions: HashMap::new(),
+        shared: Vec::new(),
+        case_sensitive,
+        root_format: RecipeFormat::from_settings(settings)?,
+        stack: Vec::new(),
+        key: None,
+        recipients: Vec::new(),
+    };
+    let raw_lines = loader.load(Path::new(file))?;
 
     let mut instructions = Vec::new();
-    for (name, partial) in partial {
-        instructions.push(partial.build(name)?);
+    for (name, mut partial) in loader.partial {
+        partial.merge_shared(&loader.shared);
+        let origin = partial.origin.take();
+        let instruction = partial.build(name).map_err(|e| match &origin {
+            Some((path, line, text)) => RecipeError::new(path, *line, text, e.to_string()).into(),
+            None => e,
+        })?;
+        instructions.push(instruction);
     }
 
     Ok(Recipe {
-        instructions,
+        instructions: order_instructions(instructions)?,
+        shared: loader.shared,
         case_sensitive,
         raw_lines,
+        key: loader.key,
+        recipients: loader.recipients,
     })
 }
 
+/// Accumulates instructions across a recipe and the files it includes or extends.
+struct RecipeLoader {
+    // Instructions are kept in the order they first appear.
+    partial: Vec<(String, PartialInstruction)>,
+    positions: HashMap<String, usize>,
+    shared: Vec<SharedValues>,
+    case_sensitive: bool,
+    root_format: Option<RecipeFormat>,
+    stack: Vec<PathBuf>,
+    key: Option<String>,
+    recipients: Vec<String>,
+}
+
+impl RecipeLoader {
+    /// Loads `path` into the accumulated instructions and returns its own raw lines.
+    ///
+    /// Before the first section a recipe may declare `extends = base.cfg` (once) and any
+    /// number of `include = other.cfg`, both resolved relative to the declaring file, and
+    /// `key = <id>` or `recipients = age1..., ...` to choose how its secrets are encrypted.
+    /// Values read later override values with the same key read earlier.
+    fn load(&mut self, path: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+        let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        if let Some(start) = self.stack.iter().position(|p| *p == canonical) {
+            let chain: Vec<String> = self.stack[start..]
+                .iter()
+                .chain(std::iter::once(&canonical))
+                .map(|p| p.display().to_string())
+                .collect();
+            return Err(format!("Recipe include cycle: {}", chain.join(" -> ")).into());
+        }
+
+        // An explicit format only applies to the recipe named on the command line.
+        let format = match self.root_format {
+            Some(format) if self.stack.is_empty() => format,
+            _ => RecipeFormat::detect(path),
+        };
+        let content = read_file(&path.to_string_lossy())?;
+        self.stack.push(canonical);
+        let raw_lines: Vec<String> = content.lines().map(str::to_string).collect();
+        match format {
+            RecipeFormat::Ini => self.load_ini(path, &raw_lines)?,
+            RecipeFormat::Toml => {
+                let entries = toml_entries(&content).map_err(|(line, message)| {
+                    let text = raw_lines.get(line.saturating_sub(1)).map_or("", |l| l.as_str());
+                    RecipeError::new(path, line, text, message)
+                })?;
+                self.load_entries(path, &raw_lines, entries)?;
+            }
+            RecipeFormat::Yaml | RecipeFormat::Json => {
+                let entries = tree_value(&content, format)
+                    .and_then(|value| value_entries(&value, &raw_lines))
+                    .map_err(|(line, message)| {
+                        let text = raw_lines.get(line.saturating_sub(1)).map_or("", |l| l.as_str());
+                        RecipeError::new(path, line, text, message)
+                    })?;
+                self.load_entries(path, &raw_lines, entries)?;
+            }
+        }
+        self.stack.pop();
+        Ok(raw_lines)
+    }
+
+    fn load_ini(&mut self, path: &Path, raw_lines: &[String]) -> Result<(), Box<dyn Error>> {
+        let mut current_section: Option<String> = None;
+        let mut extended = false;
+        let mut seen_sections: HashSet<String> = HashSet::new();
+        let mut seen_keys: HashSet<String> = HashSet::new();
+
+        for logical in logical_lines(raw_lines) {
+            let idx = logical.start;
+            let line = &raw_lines[idx];
+            let at = |message: String| RecipeError::new(path, idx + 1, line, message);
+            let trimmed = logical.text.as_str();
+            if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with(';') {
+                continue;
+            }
+            if trimmed.starts_with('[') && trimmed.ends_with(']') {
+                let name = trimmed.trim_matches(|c| c == '[' || c == ']').to_string();
+                self.open_section(&name, (path.to_path_buf(), idx + 1, line.to_string()))
+                    .map_err(|e| at(e.to_string()))?;
+                if !seen_sections.insert(name.clone()) {
+                    return Err(at(format!("Duplicate section [{}]", name)).into());
+                }
+                seen_keys.clear();
+                current_section = Some(name);
+                continue;
+            }
+            let (key, value) = trimmed.split_once('=').ok_or_else(|| {
+                at("Expected a [section] header or a 'key = value' pair".to_string())
+            })?;
+            let key = key.trim().to_string();
+            let value = unquote(value.trim()).map_err(|e| at(e.to_string()))?;
+            if let Some(section) = &current_section {
+                let (_, _, part) = split_section(section).map_err(|e| at(e.to_string()))?;
+                let seen = if part == "template" {
+                    key.to_lowercase()
+                } else {
+                    normalize_key(&key, self.case_sensitive)
+                };
+                if !seen_keys.insert(seen) {
+                    return Err(at(format!("Duplicate key '{}' in [{}]", key, section)).into());
+                }
+                self.add_entry(section, &key, &value)
+                    .map_err(|e| at(e.to_string()))?;
+            } else {
+                self.load_directive(path, &key, &value, &mut extended)
+                    .map_err(|e| locate(e, at))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads entries of tree-shaped formats, whose table path plays the role of the section.
+    fn load_entries(
+        &mut self,
+        path: &Path,
+        raw_lines: &[String],
+        entries: Vec<TreeEntry>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut extended = false;
+        let mut opened: HashSet<String> = HashSet::new();
+        for entry in entries {
+            let text = raw_lines
+                .get(entry.line.saturating_sub(1))
+                .map_or("", |l| l.as_str());
+            let at = |message: String| RecipeError::new(path, entry.line, text, message);
+            if entry.section.is_empty() {
+                self.load_directive(path, &entry.key, &entry.value, &mut extended)
+                    .map_err(|e| locate(e, at))?;
+                continue;
+            }
+            let section = entry.section.join(":");
+            if opened.insert(section.clone()) {
+                self.open_section(&section, (path.to_path_buf(), entry.line, text.to_string()))
+                    .map_err(|e| at(e.to_string()))?;
+            }
+            self.add_entry(&section, &entry.key, &entry.value)
+                .map_err(|e| at(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Validates a section name and records where its instruction was first declared.
+    fn open_section(
+        &mut self,
+        section: &str,
+        origin: (PathBuf, usize, String),
+    ) -> Result<(), Box<dyn Error>> {
+        let (env, inst_name, part) = split_section(section)?;
+        let parts: &[&str] = if inst_name == SHARED_NAME {
+            &["variables", "secrets"]
+        } else {
+            &["template", "variables", "secrets"]
+        };
+        if !parts.contains(&part) {
+            return Err(format!("Unsupported section '{}' in [{}]", part, section).into());
+        }
+        if inst_name != SHARED_NAME {
+            self.entry(env, inst_name).origin.get_or_insert(origin);
+        }
+        Ok(())
+    }
+
+    fn add_entry(&mut self, section: &str, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        let (env, inst_name, part) = split_section(section)?;
+        if inst_name == SHARED_NAME {
+            self.add_shared(env, part, key, value)
+        } else {
+            let case_sensitive = self.case_sensitive;
+            self.entry(env, inst_name)
+                .add_value(part, key, value, case_sensitive)
+        }
+    }
+
+    /// Handles `include`/`extends`, the only keys allowed outside of a section.
+    fn load_directive(
+        &mut self,
+        path: &Path,
+        key: &str,
+        value: &str,
+        extended: &mut bool,
+    ) -> Result<(), Box<dyn Error>> {
+        match key.to_lowercase().as_str() {
+            // Only the recipe being encrypted decides its key, not the files it includes.
+            "key" => {
+                if self.stack.len() == 1 {
+                    self.key = Some(value.to_string());
+                }
+                return Ok(());
+            }
+            "recipients" => {
+                if self.stack.len() == 1 {
+                    self.recipients = split_list(value);
+                }
+                return Ok(());
+            }
+            "include" => {}
+            "extends" if *extended => return Err("A recipe can extend only one recipe".into()),
+            "extends" => *extended = true,
+            _ => return Err(format!("Key '{}' is outside of any section", key).into()),
+        }
+        self.load(&resolve_relative(path, value))?;
+        Ok(())
+    }
+
+    fn entry(&mut self, env: Option<&str>, name: &str) -> &mut PartialInstruction {
+        let qualified = qualified_name(env, name);
+        let pos = match self.positions.get(&qualified) {
+            Some(pos) => *pos,
+            None => {
+                self.partial.push((
+                    qualified.clone(),
+                    PartialInstruction {
+                        env: env.map(str::to_string),
+                        ..Default::default()
+                    },
+                ));
+                self.positions.insert(qualified, self.partial.len() - 1);
+                self.partial.len() - 1
+            }
+        };
+        &mut self.partial[pos].1
+    }
+
+    fn add_shared(
+        &mut self,
+        env: Option<&str>,
+        part: &str,
+        key: &str,
+        value: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let pos = match self.shared.iter().position(|s| s.env.as_deref() == env) {
+            Some(pos) => pos,
+            None => {
+                self.shared.push(SharedValues {
+                    env: env.map(str::to_string),
+                    ..Default::default()
+                });
+                self.shared.len() - 1
+            }
+        };
+        let shared = &mut self.shared[pos];
+        let key = normalize_key(key, self.case_sensitive);
+        match part {
+            "variables" => shared.variables.insert(key, value.to_string()),
+            "secrets" => shared.secrets.insert(key, value.to_string()),
+            other => {
+                let section = match env {
+                    Some(env) => format!("{}:{}:{}", env, SHARED_NAME, other),
+                    None => other.to_string(),
+                };
+                return Err(format!(
+                    "Section [{}] only supports variables and secrets",
+                    section
+                )
+                .into());
+            }
+        };
+        Ok(())
+    }
+}
+
+/// Keeps errors raised by nested recipes as they are and locates the others at `at`.
+fn locate(err: Box<dyn Error>, at: impl Fn(String) -> RecipeError) -> Box<dyn Error> {
+    if err.is::<RecipeError>() {
+        err
+    } else {
+        at(err.to_string()).into()
+    }
+}
+
+/// A value read from a tree-shaped recipe, with the table path leading to it.
+struct TreeEntry {
+    section: Vec<String>,
+    key: String,
+    value: String,
+    line: usize,
+}
+
+/// Flattens a TOML recipe into entries, `[dev.app.variables]` becoming `dev:app:variables`.
+/// Errors carry the 1-based line they point at.
+fn toml_entries(content: &str) -> Result<Vec<TreeEntry>, (usize, String)> {
+    let line_of = |offset: usize| content[..offset.min(content.len())].matches('\n').count() + 1;
+    let document = ImDocument::parse(content).map_err(|e| {
+        let line = e.span().map_or(1, |span| line_of(span.start));
+        (line, e.message().to_string())
+    })?;
+
+    fn walk(
+        table: &dyn TableLike,
+        section: &[String],
+        line_of: &dyn Fn(usize) -> usize,
+        out: &mut Vec<TreeEntry>,
+    ) -> Result<(), (usize, String)> {
+        for (key, item) in table.iter() {
+            let line = table
+                .get_key_value(key)
+                .and_then(|(k, _)| k.span())
+                .map_or(1, |span| line_of(span.start));
+            if let Some(child) = item.as_table_like() {
+                let mut path = section.to_vec();
+                path.push(key.to_string());
+                walk(child, &path, line_of, out)?;
+            } else if let (true, Some(TomlValue::Array(items))) =
+                (section.is_empty(), item.as_value())
+            {
+                // Top-level arrays list several directives, e.g. `include = ["a.toml", "b.toml"]`.
+                for value in items.iter() {
+                    out.push(TreeEntry {
+                        section: Vec::new(),
+                        key: key.to_string(),
+                        value: value.as_str().map_or_else(|| value.to_string(), str::to_string),
+                        line,
+                    });
+                }
+            } else if let Some(value) = item.as_value() {
+                let value = match value {
+                    TomlValue::String(s) => s.value().to_string(),
+                    TomlValue::Array(items) => items
+                        .iter()
+                        .map(|v| v.as_str().map_or_else(|| v.to_string(), str::to_string))
+                        .map(|v| v.trim().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    other => other.to_string().trim().to_string(),
+                };
+                out.push(TreeEntry {
+                    section: section.to_vec(),
+                    key: key.to_string(),
+                    value,
+                    line,
+                });
+            } else {
+                return Err((line, format!("Arrays of tables are not supported for '{}'", key)));
+            }
+        }
+        Ok(())
+    }
+
+    let mut entries = Vec::new();
+    walk(document.as_table(), &[], &line_of, &mut entries)?;
+    Ok(entries)
+}
+
+/// Reads a YAML or JSON recipe into a JSON value, keeping key order.
+fn tree_value(content: &str, format: RecipeFormat) -> Result<JsonValue, (usize, String)> {
+    if format == RecipeFormat::Yaml {
+        serde_yaml::from_str(content).map_err(|e| {
+            let line = e.location().map_or(1, |l| l.line());
+            (line, e.to_string())
+        })
+    } else {
+        serde_json::from_str(content).map_err(|e| (e.line().max(1), e.to_string()))
+    }
+}
+
+/// Flattens a YAML/JSON recipe the same way as TOML: `{"dev": {"app": {"variables": ..}}}`
+/// becomes `dev:app:variables`. These parsers keep no positions, so each entry is located
+/// at the next line mentioning its key.
+fn value_entries(
+    value: &JsonValue,
+    raw_lines: &[String],
+) -> Result<Vec<TreeEntry>, (usize, String)> {
+    fn scalar(value: &JsonValue) -> String {
+        match value {
+            JsonValue::String(s) => s.clone(),
+            JsonValue::Null => String::new(),
+            JsonValue::Array(items) => items.iter().map(scalar).collect::<Vec<_>>().join(", "),
+            other => other.to_string(),
+        }
+    }
+
+    fn walk(
+        object: &JsonMap<String, JsonValue>,
+        section: &[String],
+        raw_lines: &[String],
+        cursor: &mut usize,
+        out: &mut Vec<TreeEntry>,
+    ) -> Result<(), (usize, String)> {
+        for (key, value) in object {
+            let found = raw_lines
+                .iter()
+                .enumerate()
+                .skip(*cursor)
+                .find(|(_, line)| line.contains(key.as_str()) && line.contains(':'))
+                .map(|(idx, _)| idx);
+            if let Some(idx) = found {
+                *cursor = idx;
+            }
+            let line = *cursor + 1;
+            match value {
+                JsonValue::Object(child) => {
+                    let mut path = section.to_vec();
+                    path.push(key.clone());
+                    walk(child, &path, raw_lines, cursor, out)?;
+                }
+                JsonValue::Array(items) if section.is_empty() => {
+                    for item in items {
+                        out.push(TreeEntry {
+                            section: Vec::new(),
+                            key: key.clone(),
+                            value: scalar(item),
+                            line,
+                        });
+                    }
+                }
+                JsonValue::Array(items) if items.iter().any(|i| i.is_object()) => {
+                    return Err((line, format!("Lists of tables are not supported for '{}'", key)));
+                }
+                other => out.push(TreeEntry {
+                    section: section.to_vec(),
+                    key: key.clone(),
+                    value: scalar(other),
+                    line,
+                }),
+            }
+        }
+        Ok(())
+    }
+
+    let object = value
+        .as_object()
+        .ok_or_else(|| (1, "A recipe must be a mapping at the top level".to_string()))?;
+    let mut entries = Vec::new();
+    walk(object, &[], raw_lines, &mut 0, &mut entries)?;
+    Ok(entries)
+}
+
+/// One recipe entry and the physical lines (`start..end`) it spans.
+struct LogicalLine {
+    start: usize,
+    end: usize,
+    text: String,
+}
+
+/// Joins INI continuation lines: a non-empty line indented deeper than the `key = value`
+/// line above it continues that value, separated by a newline. Comment lines are never
+/// continuations, however indented, and end the value. Every other line is its own entry,
+/// trimmed.
+fn logical_lines(lines: &[String]) -> Vec<LogicalLine> {
+    let indent = |line: &str| line.len() - line.trim_start().len();
+    let mut entries: Vec<LogicalLine> = Vec::new();
+    let mut pair_indent: Option<usize> = None;
+
+    for (idx, line) in lines.iter().enumerate() {
+        let trimmed = line.trim();
+        let is_comment = trimmed.starts_with('#') || trimmed.starts_with(';');
+        if let (false, Some(level), Some(last)) = (is_comment, pair_indent, entries.last_mut()) {
+            if !trimmed.is_empty() && indent(line) > level {
+                last.text.push('\n');
+                last.text.push_str(trimmed);
+                last.end = idx + 1;
+                continue;
+            }
+        }
+        let is_pair = !is_comment && !trimmed.starts_with('[') && trimmed.contains('=');
+        pair_indent = if is_pair { Some(indent(line)) } else { None };
+        entries.push(LogicalLine {
+            start: idx,
+            end: idx + 1,
+            text: trimmed.to_string(),
+        });
+    }
+    entries
+}
+
+/// Strips surrounding quotes from a value. Double quotes accept `\n`, `\t`, `\r`, `\\`,
+/// `\"` and `\'` escapes; single quotes keep their content literally.
+fn unquote(value: &str) -> Result<String, Box<dyn Error>> {
+    let quote = match value.chars().next() {
+        Some(q @ ('"' | '\'')) => q,
+        _ => return Ok(value.to_string()),
+    };
+    if value.len() < 2 || !value.ends_with(quote) {
+        let first_line = value.lines().next().unwrap_or("");
+        return Err(format!("Unterminated quoted value {}", first_line).into());
+    }
+    let inner = &value[1..value.len() - 1];
+    if quote == '\'' {
+        return Ok(inner.to_string());
+    }
+
+    let mut output = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => output.push('\n'),
+            Some('t') => output.push('\t'),
+            Some('r') => output.push('\r'),
+            Some(other @ ('\\' | '"' | '\'')) => output.push(other),
+            Some(other) => {
+                output.push('\\');
+                output.push(other);
+            }
+            None => output.push('\\'),
+        }
+    }
+    Ok(output)
+}
+
+fn resolve_relative(from: &Path, target: &str) -> PathBuf {
+    let target = Path::new(target);
+    if target.is_absolute() {
+        return target.to_path_buf();
+    }
+    from.parent()
+        .map(|dir| dir.join(target))
+        .unwrap_or_else(|| target.to_path_buf())
+}
+
 #[derive(Debug, Default)]
 struct PartialInstruction {
+    env: Option<String>,
+    // File, line and text of the first section header that declared the instruction.
+    origin: Option<(PathBuf, usize, String)>,
     template: Option<TemplateInfo>,
     variables: HashMap<String, String>,
     secrets: HashMap<String, String>,
+    shared_secrets: HashMap<String, String>,
 }
 
 impl PartialInstruction {
+    /// Layers global values, then the instruction's environment values, then its own,
+    /// so the most specific definition of a key wins whether it is a variable or a secret.
+    fn merge_shared(&mut self, shared: &[SharedValues]) {
+        let mut variables = HashMap::new();
+        let mut secrets = HashMap::new();
+        let mut shared_secrets = HashMap::new();
+        let levels = shared
+            .iter()
+            .filter(|s| s.env.is_none())
+            .chain(shared.iter().filter(|s| s.env.is_some() && s.env == self.env))
+            .map(|s| {
+                let section = qualified_name(s.env.as_deref(), SHARED_NAME);
+                (&s.variables, &s.secrets, Some(section))
+            })
+            .chain(std::iter::once((&self.variables, &self.secrets, None)));
+        for (level_variables, level_secrets, section) in levels {
+            for (key, value) in level_variables {
+                secrets.remove(key);
+                shared_secrets.remove(key);
+                variables.insert(key.clone(), value.clone());
+            }
+            for (key, value) in level_secrets {
+                variables.remove(key);
+                secrets.insert(key.clone(), value.clone());
+                match &section {
+                    Some(section) => shared_secrets.insert(key.clone(), section.clone()),
+                    None => shared_secrets.remove(key),
+                };
+            }
+        }
+        self.variables = variables;
+        self.secrets = secrets;
+        self.shared_secrets = shared_secrets;
+    }
+
     fn add_value(
         &mut self,
         part: &str,
@@ -100,6 +845,9 @@ impl PartialInstruction {
                     user: None,
                     group: None,
                     mode: None,
+                    after: Vec::new(),
+                    key: None,
+                    recipients: Vec::new(),
                 });
                 match lower.as_str() {
                     "template" => template.template = value.to_string(),
@@ -107,6 +855,15 @@ impl PartialInstruction {
                     "user" => template.user = Some(value.to_string()),
                     "group" => template.group = Some(value.to_string()),
                     "mode" => template.mode = Some(value.to_string()),
+                    "key" => template.key = Some(value.to_string()),
+                    "recipients" => template.recipients = split_list(value),
+                    "after" => template.after.extend(
+                        value
+                            .split(',')
+                            .map(str::trim)
+                            .filter(|v| !v.is_empty())
+                            .map(str::to_string),
+                    ),
                     other => {
                         return Err(format!("Unsupported attribute '{}' in recipe", other).into())
                     }
@@ -142,23 +899,131 @@ impl PartialInstruction {
 
         Ok(Instruction {
             name,
+            env: self.env,
             is_remote: is_url(&template.template),
             template,
-            variables: self.variables,
+            variables: self
+                .variables
+                .into_iter()
+                .map(|(key, value)| (key, value.into()))
+                .collect(),
             secrets: self.secrets,
+            shared_secrets: self.shared_secrets,
+            verbatim: HashSet::new(),
         })
     }
 }
 
-fn split_section(section: &str) -> Result<(&str, &str), Box<dyn Error>> {
-    let mut parts = section.rsplitn(2, ':');
-    let part = parts
-        .next()
-        .ok_or("Malformed section: missing part after ':'")?;
-    let name = parts
-        .next()
-        .ok_or("Malformed section: missing instruction name")?;
-    Ok((name, part))
+/// Sorts instructions so that every `after` dependency runs first, otherwise keeping
+/// file order. Names in `after` resolve inside the instruction's own environment first.
+fn order_instructions(instructions: Vec<Instruction>) -> Result<Vec<Instruction>, Box<dyn Error>> {
+    let index: HashMap<&str, usize> = instructions
+        .iter()
+        .enumerate()
+        .map(|(idx, i)| (i.name.as_str(), idx))
+        .collect();
+
+    let mut deps: Vec<Vec<usize>> = Vec::with_capacity(instructions.len());
+    for instruction in &instructions {
+        let mut resolved = Vec::new();
+        for dep in &instruction.template.after {
+            let scoped = instruction
+                .env
+                .as_deref()
+                .and_then(|env| index.get(qualified_name(Some(env), dep).as_str()));
+            let idx = scoped.or_else(|| index.get(dep.as_str())).ok_or_else(|| {
+                format!(
+                    "Instruction '{}' declares after = '{}' but no such instruction exists",
+                    instruction.name, dep
+                )
+            })?;
+            resolved.push(*idx);
+        }
+        deps.push(resolved);
+    }
+
+    if let Some(cycle) = find_cycle(&deps) {
+        let names: Vec<&str> = cycle.iter().map(|&i| instructions[i].name.as_str()).collect();
+        return Err(format!("Instruction dependency cycle: {}", names.join(" -> ")).into());
+    }
+
+    let mut placed = vec![false; instructions.len()];
+    let mut order = Vec::with_capacity(instructions.len());
+    while order.len() < instructions.len() {
+        let next = (0..instructions.len())
+            .find(|&i| !placed[i] && deps[i].iter().all(|&d| placed[d]))
+            .ok_or("Instruction dependencies could not be resolved")?;
+        placed[next] = true;
+        order.push(next);
+    }
+
+    let mut slots: Vec<Option<Instruction>> = instructions.into_iter().map(Some).collect();
+    Ok(order.into_iter().filter_map(|i| slots[i].take()).collect())
+}
+
+/// Returns the first dependency cycle found, closed with its starting node.
+fn find_cycle(deps: &[Vec<usize>]) -> Option<Vec<usize>> {
+    fn visit(
+        node: usize,
+        deps: &[Vec<usize>],
+        state: &mut [u8],
+        stack: &mut Vec<usize>,
+    ) -> Option<Vec<usize>> {
+        state[node] = 1;
+        stack.push(node);
+        for &next in &deps[node] {
+            if state[next] == 1 {
+                let start = stack.iter().position(|&n| n == next).unwrap_or(0);
+                let mut cycle = stack[start..].to_vec();
+                cycle.push(next);
+                return Some(cycle);
+            }
+            if state[next] == 0 {
+                if let Some(cycle) = visit(next, deps, state, stack) {
+                    return Some(cycle);
+                }
+            }
+        }
+        stack.pop();
+        state[node] = 2;
+        None
+    }
+
+    let mut state = vec![0u8; deps.len()];
+    for node in 0..deps.len() {
+        if state[node] == 0 {
+            if let Some(cycle) = visit(node, deps, &mut state, &mut Vec::new()) {
+                return Some(cycle);
+            }
+        }
+    }
+    None
+}
+
+/// Instruction name used by sections shared across instructions.
+const SHARED_NAME: &str = "*";
+
+/// Splits `[<instruction>:<part>]` or `[<env>:<instruction>:<part>]` into its pieces.
+/// A bare `[<part>]` is shared by every instruction and reported as `*`.
+fn split_section(section: &str) -> Result<(Option<&str>, &str, &str), Box<dyn Error>> {
+    let parts: Vec<&str> = section.split(':').collect();
+    match parts.as_slice() {
+        [part] if !part.is_empty() => Ok((None, SHARED_NAME, part)),
+        [name, part] if !name.is_empty() => Ok((None, name, part)),
+        [env, name, part] if !env.is_empty() && !name.is_empty() => Ok((Some(env), name, part)),
+        _ => Err(format!(
+            "Malformed section '{}': use [<instruction>:<part>] or [<env>:<instruction>:<part>]",
+            section
+        )
+        .into()),
+    }
+}
+
+fn qualified_name(env: Option<&str>, name: &str) -> String {
+    match env {
+        Some(env) => format!("{}:{}", env, name),
+        None => name.to_string(),
+    }
 }
 
 fn normalize_key(key: &str, case_sensitive: bool) -> String {
@@ -169,10 +1034,14 @@ fn normalize_key(key: &str, case_sensitive: bool) -> String {
     }
 }
 
+/// Decrypts secrets into variables; `file:`, `cmd:` and `env:` secrets are resolved through
+/// their provider instead, if `providers` enables it. `crypto` may be `None` when every
+/// secret comes from a provider. With `BIND_SECRETS`, ciphertext must be bound.
 pub fn decrypt_secrets(
     instructions: &mut [Instruction],
-    crypto: &Crypto,
+    crypto: Option<&Crypto>,
     case_sensitive: bool,
+    providers: &Providers,
 ) -> Result<(), Box<dyn Error>> {
     for instruction in instructions {
         if instruction.secrets.is_empty() {
@@ -182,68 +1051,537 @@ pub fn decrypt_secrets(
             instruction.variables = HashMap::new();
         }
         for (key, secret) in instruction.secrets.clone() {
-            let decrypted_value = crypto.decrypt(&secret)?;
-            instruction
-                .variables
-                .insert(normalize_key(&key, case_sensitive), decrypted_value);
+            let decrypted_value = match provider::resolve(&secret, providers) {
+                Ok(Some(value)) => value,
+                Ok(None) => {
+                    let crypto = crypto.ok_or("A secret key is required")?;
+                    decrypt_bound(crypto, instruction, &key, &secret)?
+                }
+                Err(err) => {
+                    return Err(format!("Secret '{}' of [{}]: {}", key, instruction.name, err).into())
+                }
+            };
+            let key = normalize_key(&key, case_sensitive);
+            instruction.verbatim.insert(key.clone());
+            instruction.variables.insert(key, decrypted_value);
         }
     }
     Ok(())
 }
 
-pub fn encrypt_recipe_file(path: &str, settings: &Settings, crypto: &Crypto) -> Result<(), Box<dyn Error>> {
-    let recipe = parse(path, settings, Some(true))?;
-    let mut encrypted = HashMap::new();
-    for instr in recipe.instructions {
-        if instr.secrets.is_empty() {
-            continue;
-        }
-        for (key, secret) in instr.secrets {
-            let cipher = crypto.encrypt(&secret)?;
-            let map_key = format!("{}::{}", instr.name, key);
-            encrypted.insert(map_key, cipher);
+/// Decrypts a secret of `instruction`. Bound shared secrets must be bound to the
+/// `[<env>:*:secrets]` or `[*:secrets]` section they were merged from, the others to the
+/// instruction itself.
+fn decrypt_bound(
+    crypto: &Crypto,
+    instruction: &Instruction,
+    key: &str,
+    secret: &str,
+) -> Result<SecretString, Box<dyn Error>> {
+    if !Crypto::is_bound(secret) {
+        if crypto.binds() && Crypto::is_ciphertext(secret) {
+            return Err(format!(
+                "Secret '{}' of [{}] is not bound to its instruction (BIND_SECRETS is on). \
+                 Run 'bind' on the recipe.",
+                key, instruction.name
+            )
+            .into());
         }
+        return crypto.decrypt(secret);
     }
+    let section = instruction
+        .shared_secrets
+        .get(key)
+        .unwrap_or(&instruction.name);
+    crypto.decrypt_secret(secret, section, key)
+}
 
-    let mut output = String::new();
-    let mut current_name: Option<String> = None;
-    let mut in_secrets = false;
-
-    for line in recipe.raw_lines {
-        let trimmed = line.trim();
-        if trimmed.starts_with('[') && trimmed.ends_with(']') {
-            let (name, part) = split_section(trimmed.trim_matches(|c| c == '[' || c == ']'))?;
-            current_name = Some(name.to_string());
-            in_secrets = part == "secrets";
-            output.push_str(&line);
-            output.push('\n');
-            continue;
+/// Expands `${NAME}` references in the variables written in the recipe. Decrypted secrets
+/// and `env:` values can be referenced but are inserted as they are, never expanded.
+/// `$${` yields a literal `${`.
+pub fn interpolate_variables(
+    instructions: &mut [Instruction],
+    case_sensitive: bool,
+) -> Result<(), Box<dyn Error>> {
+    for instruction in instructions {
+        let mut resolved = HashMap::new();
+        let mut keys: Vec<String> = instruction.variables.keys().cloned().collect();
+        keys.sort();
+        for key in keys {
+            resolve_variable(
+                &key,
+                instruction,
+                case_sensitive,
+                &mut resolved,
+                &mut Vec::new(),
+            )?;
         }
+        instruction.variables = resolved;
+    }
+    Ok(())
+}
 
-        if in_secrets {
-            if let Some((key, _)) = trimmed.split_once('=') {
-                if let Some(name) = &current_name {
-                    let map_key = format!("{}::{}", name, key.trim());
-                    if let Some(value) = encrypted.get(&map_key) {
-                        output.push_str(&format!("{} = {}\n", key.trim(), value));
-                        continue;
-                    }
+/// Names referenced through `${NAME}` in a value, ignoring `$${` escapes.
+pub fn interpolation_refs(value: &str, case_sensitive: bool) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut rest = value;
+    while let Some(idx) = rest.find('$') {
+        rest = &rest[idx..];
+        if rest.starts_with("$${") {
+            rest = &rest[3..];
+        } else if let Some(after) = rest.strip_prefix("${") {
+            match after.find('}') {
+                Some(end) => {
+                    names.push(normalize_key(after[..end].trim(), case_sensitive));
+                    rest = &after[end + 1..];
                 }
+                None => break,
             }
+        } else {
+            rest = &rest[1..];
         }
-
-        output.push_str(&line);
-        output.push('\n');
     }
+    names
+}
 
-    write_file(path, output.trim_end_matches('\n'))?;
-    Ok(())
+/// Part of an interpolated value: recipe text or the resolved value of a reference.
+enum Piece<'a> {
+    Text(&'a str),
+    Value(SecretString),
 }
 
-#[cfg(test)]
+impl Piece<'_> {
+    fn as_str(&self) -> &str {
+        match self {
+            Piece::Text(text) => text,
+            Piece::Value(value) => value,
+        }
+    }
+}
+
+fn resolve_variable(
+    key: &str,
+    instruction: &Instruction,
+    case_sensitive: bool,
+    resolved: &mut HashMap<String, SecretString>,
+    stack: &mut Vec<String>,
+) -> Result<SecretString, Box<dyn Error>> {
+    if let Some(value) = resolved.get(key) {
+        return Ok(value.clone());
+    }
+    if let Some(start) = stack.iter().position(|k| k == key) {
+        let mut cycle = stack[start..].to_vec();
+        cycle.push(key.to_string());
+        return Err(format!(
+            "Reference cycle in [{}] {}: {}",
+            instruction.name,
+            stack[start],
+            cycle.join(" -> ")
+        )
+        .into());
+    }
+
+    let raw = &instruction.variables[key];
+    if instruction.verbatim.contains(key) {
+        resolved.insert(key.to_string(), raw.clone());
+        return Ok(raw.clone());
+    }
+    stack.push(key.to_string());
+    // Pieces first, then one buffer of the final size (as `BakerTemplate::replace` does): a
+    // growing buffer would leave copies of the secrets in it behind on every reallocation.
+    let mut pieces: Vec<Piece> = Vec::new();
+    let mut rest = raw.as_str();
+    while let Some(idx) = rest.find('$') {
+        pieces.push(Piece::Text(&rest[..idx]));
+        rest = &rest[idx..];
+        if rest.starts_with("$${") {
+            pieces.push(Piece::Text("${"));
+            rest = &rest[3..];
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after.find('}').ok_or_else(|| {
+                format!("Unclosed '${{' in [{}] {}", instruction.name, key)
+            })?;
+            let name = normalize_key(after[..end].trim(), case_sensitive);
+            if !instruction.variables.contains_key(&name) {
+                return Err(format!(
+                    "Unresolved reference '${{{}}}' in [{}] {}",
+                    after[..end].trim(),
+                    instruction.name,
+                    key
+                )
+                .into());
+            }
+            let value = resolve_variable(&name, instruction, case_sensitive, resolved, stack)?;
+            pieces.push(Piece::Value(value));
+            rest = &after[end + 1..];
+        } else {
+            pieces.push(Piece::Text("$"));
+            rest = &rest[1..];
+        }
+    }
+    pieces.push(Piece::Text(rest));
+    stack.pop();
+
+    let length = pieces.iter().map(|piece| piece.as_str().len()).sum();
+    let mut output = SecretString::new(String::with_capacity(length));
+    for piece in &pieces {
+        output.push_str(piece.as_str());
+    }
+
+    resolved.insert(key.to_string(), output.clone());
+    Ok(output)
+}
+
+/// Outcome of `encrypt_recipe_file`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct EncryptSummary {
+    pub encrypted: usize,
+    pub skipped: usize,
+    /// Skipped values that look encrypted but do not decrypt with the current key.
+    pub foreign: usize,
+    /// `file:`, `cmd:` and `env:` references, resolved when the recipe runs.
+    pub providers: usize,
+}
+
+/// Encrypts the plaintext secrets written in `path`. Values already in ciphertext form are
+/// left alone, so running it repeatedly is safe.
+pub fn encrypt_recipe_file(
+    path: &str,
+    settings: &Settings,
+    crypto: &Crypto,
+) -> Result<EncryptSummary, Box<dyn Error>> {
+    let mut summary = EncryptSummary::default();
+    let keys = EncryptionKeys::of(&parse(path, settings, Some(true))?, settings);
+    rewrite_secrets(path, settings, |name, key, value| {
+        if provider::is_reference(value) {
+            summary.providers += 1;
+            return Ok(None);
+        }
+        if Crypto::is_ciphertext(value) {
+            summary.skipped += 1;
+            if crypto.decrypt_secret(value, name, key).is_err() {
+                summary.foreign += 1;
+            }
+            return Ok(None);
+        }
+        summary.encrypted += 1;
+        let target = keys.for_instruction(name);
+        Ok(Some(crypto.encrypt_secret(target, value, name, key)?))
+    })?;
+    Ok(summary)
+}
+
+/// How a recipe's secrets are encrypted, from the most specific choice: an instruction's
+/// `recipients` or `key` attribute, the recipe's top-level `recipients` or `key`, the
+/// `RECIPIENTS` setting, otherwise the default key. Recipients win over a key at the same level.
+pub struct EncryptionKeys {
+    default: Target,
+    instructions: HashMap<String, Target>,
+}
+
+enum Target {
+    Key(Option<String>),
+    Recipients(Vec<String>),
+}
+
+impl Target {
+    fn of(key: &Option<String>, recipients: &[String]) -> Option<Target> {
+        if !recipients.is_empty() {
+            Some(Target::Recipients(recipients.to_vec()))
+        } else {
+            key.clone().map(|key| Target::Key(Some(key)))
+        }
+    }
+}
+
+impl EncryptionKeys {
+    pub fn of(recipe: &Recipe, settings: &Settings) -> Self {
+        let instructions = recipe
+            .instructions
+            .iter()
+            .filter_map(|i| {
+                Target::of(&i.template.key, &i.template.recipients).map(|t| (i.name.clone(), t))
+            })
+            .collect();
+        let default = Target::of(&recipe.key, &recipe.recipients)
+            .or_else(|| Target::of(&None, &settings.recipients))
+            .unwrap_or(Target::Key(None));
+        EncryptionKeys {
+            default,
+            instructions,
+        }
+    }
+
+    pub fn for_instruction(&self, name: &str) -> EncryptTarget<'_> {
+        match self.instructions.get(name).unwrap_or(&self.default) {
+            Target::Key(key) => EncryptTarget::Key(key.as_deref()),
+            Target::Recipients(recipients) => EncryptTarget::Recipients(recipients),
+        }
+    }
+}
+
+/// (instruction, key, value) of a secret written in a recipe file.
+pub type SecretEntry = (String, String, SecretString);
+
+/// Secrets written in `path` with their plaintext. Values that are not ciphertext are
+/// returned as they are written.
+pub fn decrypt_recipe_file(
+    path: &str,
+    settings: &Settings,
+    crypto: &Crypto,
+) -> Result<Vec<SecretEntry>, Box<dyn Error>> {
+    let mut secrets = Vec::new();
+    parse(path, settings, Some(true))?;
+    let format = RecipeFormat::for_file(Path::new(path), settings)?;
+    rewrite_secret_content(&read_file(path)?, format, &mut |name, key, value| {
+        let plain = if Crypto::is_ciphertext(value) {
+            crypto.decrypt_secret(value, name, key)?
+        } else {
+            value.into()
+        };
+        secrets.push((name.to_string(), key.to_string(), plain));
+        Ok(None)
+    })?;
+    Ok(secrets)
+}
+
+/// Receives (instruction, key, current value) and returns a replacement, if any.
+type SecretTransform<'a> =
+    dyn FnMut(&str, &str, &str) -> Result<Option<String>, Box<dyn Error>> + 'a;
+
+/// Offers every secret written in `path` to `transform` as (instruction, key, value) and
+/// writes back the values it replaces. The instruction is `*` or `<env>:*` for shared
+/// secrets. The recipe is validated first and the file is only written when something
+/// changed; returns whether it was.
+pub fn rewrite_secrets<F>(
+    path: &str,
+    settings: &Settings,
+    mut transform: F,
+) -> Result<bool, Box<dyn Error>>
+where
+    F: FnMut(&str, &str, &str) -> Result<Option<String>, Box<dyn Error>>,
+{
+    match rewritten_secrets(path, settings, &mut transform)? {
+        Some(output) => {
+            write_file(path, &output)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Same as `rewrite_secrets` without touching the file: returns its new text when a value
+/// was replaced.
+pub fn rewritten_secrets(
+    path: &str,
+    settings: &Settings,
+    transform: &mut SecretTransform,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let recipe = parse(path, settings, Some(true))?;
+    let format = RecipeFormat::for_file(Path::new(path), settings)?;
+    // `raw_lines` holds the lines of `path` itself, never those of included recipes.
+    let content = match format {
+        RecipeFormat::Ini => recipe.raw_lines.join("\n"),
+        _ => read_file(path)?,
+    };
+    rewrite_secret_content(&content, format, transform)
+}
+
+/// Same as `rewrite_secrets` on recipe text already in memory, without resolving includes.
+/// Returns the new text when a value was replaced.
+pub fn rewrite_secret_content(
+    content: &str,
+    format: RecipeFormat,
+    transform: &mut SecretTransform,
+) -> Result<Option<String>, Box<dyn Error>> {
+    match format {
+        RecipeFormat::Ini => rewrite_ini_secrets(content, transform),
+        RecipeFormat::Toml => rewrite_toml_secrets(content, transform),
+        format => rewrite_tree_secrets(content, format, transform),
+    }
+}
+
+fn rewrite_ini_secrets(
+    content: &str,
+    transform: &mut SecretTransform,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let raw_lines: Vec<String> = content.lines().map(str::to_string).collect();
+    let mut output = String::new();
+    let mut changed = false;
+    let mut current_name: Option<String> = None;
+    let mut in_secrets = false;
+
+    for logical in logical_lines(&raw_lines) {
+        let trimmed = logical.text.as_str();
+        if trimmed.starts_with('[') && trimmed.ends_with(']') {
+            let (env, name, part) =
+                split_section(trimmed.trim_matches(|c| c == '[' || c == ']'))?;
+            current_name = Some(qualified_name(env, name));
+            in_secrets = part == "secrets";
+        } else if in_secrets && !trimmed.starts_with('#') && !trimmed.starts_with(';') {
+            if let (Some((key, value)), Some(name)) = (trimmed.split_once('='), &current_name) {
+                // Only secrets written in this file are offered; inherited ones stay in
+                // the recipe that declares them. Continuation lines collapse into the
+                // single new value.
+                let current = unquote(value.trim())?;
+                if let Some(replaced) = transform(name, key.trim(), &current)? {
+                    output.push_str(&format!("{} = {}\n", key.trim(), quote_ini(&replaced)));
+                    changed = true;
+                    continue;
+                }
+            }
+        }
+
+        for line in &raw_lines[logical.start..logical.end] {
+            output.push_str(line);
+            output.push('\n');
+        }
+    }
+
+    Ok(changed.then(|| output.trim_end_matches('\n').to_string()))
+}
+
+/// Quotes a value when reading it back unquoted would change it.
+fn quote_ini(value: &str) -> String {
+    let needs_quotes = value.contains(['\n', '\r'])
+        || value.trim() != value
+        || value.starts_with(['"', '\'']);
+    if !needs_quotes {
+        return value.to_string();
+    }
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            other => quoted.push(other),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Instruction name of a `secrets` table found at `path` in a tree-shaped recipe.
+fn tree_instruction(path: &[String]) -> String {
+    if path.is_empty() {
+        SHARED_NAME.to_string()
+    } else {
+        path.join(":")
+    }
+}
+
+/// Rewrites values of `secrets` tables in place, keeping comments and layout.
+fn rewrite_toml_secrets(
+    content: &str,
+    transform: &mut SecretTransform,
+) -> Result<Option<String>, Box<dyn Error>> {
+    fn walk(
+        table: &mut dyn TableLike,
+        path: &mut Vec<String>,
+        transform: &mut SecretTransform,
+    ) -> Result<bool, Box<dyn Error>> {
+        let in_secrets = path.last().map(String::as_str) == Some("secrets");
+        let mut changed = false;
+        for (key, item) in table.iter_mut() {
+            if let Some(child) = item.as_table_like_mut() {
+                path.push(key.get().to_string());
+                changed |= walk(child, path, transform)?;
+                path.pop();
+                continue;
+            }
+            if !in_secrets {
+                continue;
+            }
+            if let Item::Value(value) = item {
+                let current = match value.as_str() {
+                    Some(s) => s.to_string(),
+                    None => value.to_string().trim().to_string(),
+                };
+                let name = tree_instruction(&path[..path.len() - 1]);
+                if let Some(replaced) = transform(&name, key.get(), &current)? {
+                    let decor = value.decor().clone();
+                    *value = TomlValue::from(replaced);
+                    *value.decor_mut() = decor;
+                    changed = true;
+                }
+            }
+        }
+        Ok(changed)
+    }
+
+    let mut document: DocumentMut = content.parse()?;
+    let changed = walk(document.as_table_mut(), &mut Vec::new(), transform)?;
+    Ok(changed.then(|| document.to_string()))
+}
+
+/// Rewrites values of `secrets` mappings of a YAML/JSON recipe. The text is serialized
+/// again, so YAML comments are not kept.
+fn rewrite_tree_secrets(
+    content: &str,
+    format: RecipeFormat,
+    transform: &mut SecretTransform,
+) -> Result<Option<String>, Box<dyn Error>> {
+    fn walk(
+        value: &mut JsonValue,
+        path: &mut Vec<String>,
+        transform: &mut SecretTransform,
+    ) -> Result<bool, Box<dyn Error>> {
+        let mut changed = false;
+        if let JsonValue::Object(object) = value {
+            for (key, child) in object.iter_mut() {
+                match child {
+                    JsonValue::Object(secrets) if key == "secrets" => {
+                        let name = tree_instruction(path);
+                        for (secret_key, secret) in secrets.iter_mut() {
+                            let current = match secret {
+                                JsonValue::String(current) => current.clone(),
+                                JsonValue::Number(_) | JsonValue::Bool(_) => secret.to_string(),
+                                _ => continue,
+                            };
+                            if let Some(replaced) = transform(&name, secret_key, &current)? {
+                                *secret = JsonValue::String(replaced);
+                                changed = true;
+                            }
+                        }
+                    }
+                    _ => {
+                        path.push(key.clone());
+                        changed |= walk(child, path, transform)?;
+                        path.pop();
+                    }
+                }
+            }
+        }
+        Ok(changed)
+    }
+
+    let mut document = tree_value(content, format).map_err(|(_, message)| message)?;
+    if !walk(&mut document, &mut Vec::new(), transform)? {
+        return Ok(None);
+    }
+    let output = if format == RecipeFormat::Yaml {
+        serde_yaml::to_string(&document)?
+    } else {
+        serde_json::to_string_pretty(&document)? + "\n"
+    };
+    Ok(Some(output))
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs;
+    use crate::recipient::Identity;
+    use std::time::Duration;
+
+    fn providers() -> Providers {
+        Providers {
+            enabled: vec!["env".into()],
+            command_timeout: Duration::from_secs(5),
+        }
+    }
 
     #[test]
     fn parse_splits_sections_and_variables() {
@@ -264,4 +1602,532 @@ mod tests {
         assert_eq!(instr.variables.get("host").unwrap(), "dev");
         assert_eq!(instr.secrets.get("pass").unwrap(), "val");
     }
+
+    #[test]
+    fn select_env_keeps_only_matching_instructions() {
+        let dir = std::env::temp_dir().join("baker_rust_recipe_env_test");
+        let path = dir.join("envs.cfg");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            &path,
+            "[dev:app:template]\ntemplate=tmpl\npath=dev.out\n[dev:app:variables]\nHOST=dev\n\
+             [prod:app:template]\ntemplate=tmpl\npath=prod.out\n[prod:app:variables]\nHOST=prod\n",
+        )
+        .unwrap();
+
+        let settings = Settings::load(false).unwrap();
+        let mut recipe = parse(path.to_str().unwrap(), &settings, Some(false)).unwrap();
+        assert_eq!(recipe.environments(), vec!["dev", "prod"]);
+
+        let err = recipe.clone().select_env("stage").unwrap_err().to_string();
+        assert!(err.contains("dev, prod"));
+
+        recipe.select_env("prod").unwrap();
+        assert_eq!(recipe.instructions.len(), 1);
+        let instr = &recipe.instructions[0];
+        assert_eq!(instr.name, "prod:app");
+        assert_eq!(instr.variables.get("host").unwrap(), "prod");
+    }
+
+    #[test]
+    fn instructions_keep_file_order_and_respect_after() {
+        let dir = std::env::temp_dir().join("baker_rust_recipe_order_test");
+        let path = dir.join("order.cfg");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            &path,
+            "[zeta:template]\ntemplate=t\n[web:template]\ntemplate=t\nafter=db\n\
+             [alpha:template]\ntemplate=t\n[db:template]\ntemplate=t\n",
+        )
+        .unwrap();
+
+        let settings = Settings::load(false).unwrap();
+        let recipe = parse(path.to_str().unwrap(), &settings, Some(false)).unwrap();
+        let names: Vec<&str> = recipe.instructions.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, vec!["zeta", "alpha", "db", "web"]);
+
+        fs::write(
+            &path,
+            "[a:template]\ntemplate=t\nafter=b\n[b:template]\ntemplate=t\nafter=a\n",
+        )
+        .unwrap();
+        let err = parse(path.to_str().unwrap(), &settings, Some(false))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("a -> b -> a"), "{}", err);
+    }
+
+    #[test]
+    fn extends_inherits_and_overrides_and_detects_include_cycles() {
+        let dir = std::env::temp_dir().join("baker_rust_recipe_extends_test");
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        fs::write(
+            dir.join("shared/base.cfg"),
+            "[app:template]\ntemplate=tmpl\n[app:variables]\nHOST=base\nPORT=80\n",
+        )
+        .unwrap();
+        fs::write(dir.join("shared/db.cfg"), "[db:template]\ntemplate=db\n").unwrap();
+        let child = dir.join("prod.cfg");
+        fs::write(
+            &child,
+            "extends = shared/base.cfg\ninclude = shared/db.cfg\n[app:variables]\nHOST=prod\n",
+        )
+        .unwrap();
+
+        let settings = Settings::load(false).unwrap();
+        let recipe = parse(child.to_str().unwrap(), &settings, Some(false)).unwrap();
+        let names: Vec<&str> = recipe.instructions.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, vec!["app", "db"]);
+        let app = &recipe.instructions[0];
+        assert_eq!(app.variables.get("host").unwrap(), "prod");
+        assert_eq!(app.variables.get("port").unwrap(), "80");
+        assert_eq!(recipe.raw_lines.len(), 4);
+
+        fs::write(dir.join("shared/db.cfg"), "include = ../prod.cfg\n").unwrap();
+        let err = parse(child.to_str().unwrap(), &settings, Some(false))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Recipe include cycle"), "{}", err);
+    }
+
+    #[test]
+    fn shared_values_merge_with_instruction_precedence() {
+        let dir = std::env::temp_dir().join("baker_rust_recipe_shared_test");
+        let path = dir.join("shared.cfg");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            &path,
+            "[variables]\nDOMAIN=example.com\nLOG_LEVEL=info\nPORT=80\n[secrets]\nTOKEN=global\n\
+             [dev:*:variables]\nLOG_LEVEL=debug\nPORT=8080\n\
+             [dev:app:template]\ntemplate=t\n[dev:app:variables]\nPORT=9000\nTOKEN=plain\n\
+             [prod:app:template]\ntemplate=t\n",
+        )
+        .unwrap();
+
+        let settings = Settings::load(false).unwrap();
+        let recipe = parse(path.to_str().unwrap(), &settings, Some(false)).unwrap();
+        let dev = &recipe.instructions[0];
+        assert_eq!(dev.variables.get("domain").unwrap(), "example.com");
+        assert_eq!(dev.variables.get("log_level").unwrap(), "debug");
+        assert_eq!(dev.variables.get("port").unwrap(), "9000");
+        assert_eq!(dev.variables.get("token").unwrap(), "plain");
+        assert!(dev.secrets.is_empty());
+
+        let prod = &recipe.instructions[1];
+        assert_eq!(prod.variables.get("log_level").unwrap(), "info");
+        assert_eq!(prod.secrets.get("token").unwrap(), "global");
+    }
+
+    #[test]
+    fn interpolates_references_with_escape_and_cycle_detection() {
+        let dir = std::env::temp_dir().join("baker_rust_recipe_interpolate_test");
+        let path = dir.join("interp.cfg");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            &path,
+            "[app:template]\ntemplate=t\n[app:variables]\nHOST=db\nPORT=5432\n\
+             URL=https://${HOST}:${port}/api\nLITERAL=$${HOST} costs $5\n",
+        )
+        .unwrap();
+
+        let settings = Settings::load(false).unwrap();
+        let mut recipe = parse(path.to_str().unwrap(), &settings, Some(false)).unwrap();
+        interpolate_variables(&mut recipe.instructions, false).unwrap();
+        let vars = &recipe.instructions[0].variables;
+        assert_eq!(vars.get("url").unwrap(), "https://db:5432/api");
+        assert_eq!(vars.get("literal").unwrap(), "${HOST} costs $5");
+
+        fs::write(
+            &path,
+            "[app:template]\ntemplate=t\n[app:variables]\nA=${B}\nB=${A}\nC=${MISSING}\n",
+        )
+        .unwrap();
+        let mut recipe = parse(path.to_str().unwrap(), &settings, Some(false)).unwrap();
+        let err = interpolate_variables(&mut recipe.instructions, false)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("[app] a: a -> b -> a"), "{}", err);
+
+        recipe.instructions[0].variables.remove("a");
+        recipe.instructions[0].variables.remove("b");
+        let err = interpolate_variables(&mut recipe.instructions, false)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("'${MISSING}' in [app] c"), "{}", err);
+
+        // Secret plaintext and `env:` values are inserted as they are, never expanded.
+        fs::write(
+            &path,
+            "[app:template]\ntemplate=t\n[app:variables]\nTAG=env:BAKER_TEST_INTERP_TAG\n\
+             DSN=${PASS}@${TAG}\n[app:secrets]\nPASS=env:BAKER_TEST_INTERP_PASS\n",
+        )
+        .unwrap();
+        std::env::set_var("BAKER_TEST_INTERP_TAG", "t${dsn}");
+        std::env::set_var("BAKER_TEST_INTERP_PASS", "ab${x}cd");
+        let mut recipe = parse(path.to_str().unwrap(), &settings, Some(false)).unwrap();
+        recipe.resolve_env_variables().unwrap();
+        decrypt_secrets(&mut recipe.instructions, None, false, &providers()).unwrap();
+        interpolate_variables(&mut recipe.instructions, false).unwrap();
+        let vars = &recipe.instructions[0].variables;
+        assert_eq!(vars.get("pass").unwrap(), "ab${x}cd");
+        assert_eq!(vars.get("dsn").unwrap(), "ab${x}cd@t${dsn}");
+    }
+
+    #[test]
+    fn resolves_env_variables_with_defaults() {
+        let dir = std::env::temp_dir().join("baker_rust_recipe_env_vars_test");
+        let path = dir.join("env.cfg");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            &path,
+            "[app:template]\ntemplate=t\n[app:variables]\nTAG=env:BAKER_TEST_IMAGE_TAG\n\
+             BUILD=env:BAKER_TEST_UNSET_BUILD:-latest\n",
+        )
+        .unwrap();
+        std::env::set_var("BAKER_TEST_IMAGE_TAG", "1.2.3");
+
+        let settings = Settings::load(false).unwrap();
+        let mut recipe = parse(path.to_str().unwrap(), &settings, Some(false)).unwrap();
+        recipe.resolve_env_variables().unwrap();
+        let vars = &recipe.instructions[0].variables;
+        assert_eq!(vars.get("tag").unwrap(), "1.2.3");
+        assert_eq!(vars.get("build").unwrap(), "latest");
+
+        fs::write(
+            &path,
+            "[app:template]\ntemplate=t\n[app:variables]\nTAG=env:BAKER_TEST_UNSET_TAG\n",
+        )
+        .unwrap();
+        let mut recipe = parse(path.to_str().unwrap(), &settings, Some(false)).unwrap();
+        let err = recipe.resolve_env_variables().unwrap_err().to_string();
+        assert!(err.contains("'BAKER_TEST_UNSET_TAG' is not set for [app] tag"), "{}", err);
+    }
+
+    #[test]
+    fn parse_errors_carry_file_and_line() {
+        let dir = std::env::temp_dir().join("baker_rust_recipe_errors_test");
+        let path = dir.join("broken.cfg");
+        fs::create_dir_all(&dir).unwrap();
+        let settings = Settings::load(false).unwrap();
+        let cases = [
+            ("HOST=dev\n[app:template]\ntemplate=t\n", 1, "outside of any section"),
+            ("[app:template]\ntemplate=t\noops\n", 3, "Expected a [section]"),
+            ("[app:template]\ntemplate=t\n[app:varibles]\nA=1\n", 3, "Unsupported section 'varibles'"),
+            ("[app:template]\ntemplate=t\n[app:variables]\nA=1\na=2\n", 5, "Duplicate key 'a'"),
+            ("[app:template]\ntemplate=t\n[app:template]\n", 3, "Duplicate section [app:template]"),
+            ("[app:variables]\nA=1\n", 1, "Section [app:template] is required"),
+        ];
+        for (content, line, message) in cases {
+            fs::write(&path, content).unwrap();
+            let err = parse(path.to_str().unwrap(), &settings, Some(false)).unwrap_err();
+            let err = err.downcast_ref::<RecipeError>().expect("RecipeError");
+            assert_eq!(err.line, line, "{}", err);
+            assert!(err.message.contains(message), "{}", err);
+            assert!(err.to_string().starts_with(&format!("{}:{}: ", path.display(), line)));
+        }
+    }
+
+    #[test]
+    fn multiline_and_quoted_values_round_trip_through_encrypt() {
+        let dir = std::env::temp_dir().join("baker_rust_recipe_multiline_test");
+        let path = dir.join("multi.cfg");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            &path,
+            "[app:template]\ntemplate=t\n[app:variables]\nJSON = \"{\\\"a\\\": 1}\\nend\"\n\
+             NOTE = 'literal # not a comment'\nHOST = a\n    ; disabled = b\n\
+             [app:secrets]\nCERT = -----BEGIN-----\n    abc\n    -----END-----\n\
+             # trailing comment\nOTHER = x\n",
+        )
+        .unwrap();
+
+        let settings = Settings::load(false).unwrap();
+        let recipe = parse(path.to_str().unwrap(), &settings, Some(false)).unwrap();
+        let instr = &recipe.instructions[0];
+        assert_eq!(instr.variables.get("json").unwrap(), "{\"a\": 1}\nend");
+        assert_eq!(instr.variables.get("note").unwrap(), "literal # not a comment");
+        assert_eq!(instr.variables.get("host").unwrap(), "a");
+        let cert = "-----BEGIN-----\nabc\n-----END-----";
+        assert_eq!(instr.secrets.get("cert").unwrap(), cert);
+
+        let crypto = Crypto::new(vec![7u8; 32]);
+        encrypt_recipe_file(path.to_str().unwrap(), &settings, &crypto).unwrap();
+        let rewritten = fs::read_to_string(&path).unwrap();
+        let cert_line = rewritten.lines().find(|l| l.starts_with("CERT")).unwrap();
+        assert!(Crypto::is_ciphertext(cert_line.split_once('=').unwrap().1.trim()));
+        assert!(!rewritten.contains("BEGIN") && !rewritten.contains("\n    abc"));
+        assert!(rewritten.contains("# trailing comment") && rewritten.contains("; disabled = b"));
+
+        let mut recipe = parse(path.to_str().unwrap(), &settings, Some(false)).unwrap();
+        decrypt_secrets(&mut recipe.instructions, Some(&crypto), false, &providers()).unwrap();
+        let instr = &recipe.instructions[0];
+        assert_eq!(instr.variables.get("cert").unwrap(), cert);
+        assert_eq!(instr.variables.get("other").unwrap(), "x");
+    }
+
+    #[test]
+    fn encrypt_recipe_file_uses_recipe_key_ids() {
+        let dir = std::env::temp_dir().join("baker_rust_recipe_key_id_test");
+        let path = dir.join("keys.cfg");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            &path,
+            "key = dev\n[*:secrets]\nSHARED=s\n\
+             [prod:app:template]\ntemplate=t\nkey=prod\n[prod:app:secrets]\nA=one\n\
+             [dev:app:template]\ntemplate=t\n[dev:app:secrets]\nB=two\n",
+        )
+        .unwrap();
+
+        let settings = Settings::load(false).unwrap();
+        let mut crypto = Crypto::named("dev", vec![1u8; 32]);
+        crypto.insert("prod", vec![2u8; 32]);
+        encrypt_recipe_file(path.to_str().unwrap(), &settings, &crypto).unwrap();
+
+        let recipe = parse(path.to_str().unwrap(), &settings, Some(false)).unwrap();
+        assert_eq!(recipe.key.as_deref(), Some("dev"));
+        let ids: Vec<(&str, Option<&str>)> = recipe
+            .instructions
+            .iter()
+            .flat_map(|i| {
+                let mut keys: Vec<_> = i.secrets.iter().collect();
+                keys.sort();
+                keys.into_iter().map(|(k, v)| (k.as_str(), Crypto::key_id(v)))
+            })
+            .collect();
+        assert_eq!(
+            ids,
+            vec![
+                ("a", Some("prod")),
+                ("shared", Some("dev")),
+                ("b", Some("dev")),
+                ("shared", Some("dev")),
+            ]
+        );
+    }
+
+    #[test]
+    fn recipients_encrypt_for_their_identities() {
+        let dir = std::env::temp_dir().join("baker_rust_recipe_recipients_test");
+        let path = dir.join("recipients.cfg");
+        fs::create_dir_all(&dir).unwrap();
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        fs::write(
+            &path,
+            format!(
+                "[app:template]\ntemplate=t\nrecipients={}, {}\n[app:secrets]\nA=one\n\
+                 [other:template]\ntemplate=t\n[other:secrets]\nB=two\n",
+                alice.recipient(),
+                bob.recipient()
+            ),
+        )
+        .unwrap();
+
+        let settings = Settings::load(false).unwrap();
+        let crypto = Crypto::new(vec![7u8; 32]);
+        encrypt_recipe_file(path.to_str().unwrap(), &settings, &crypto).unwrap();
+
+        let recipe = parse(path.to_str().unwrap(), &settings, Some(false)).unwrap();
+        let a = recipe.instructions[0].secrets.get("a").unwrap();
+        let b = recipe.instructions[1].secrets.get("b").unwrap();
+        assert!(a.starts_with("age\\"));
+        for identity in [alice, bob] {
+            assert_eq!(Crypto::with_identity(identity).decrypt(a).unwrap(), "one");
+        }
+        assert_eq!(crypto.decrypt(b).unwrap(), "two");
+        assert!(crypto.decrypt(a).is_err());
+    }
+
+    #[test]
+    fn bound_secrets_only_decrypt_in_their_instruction() {
+        let dir = std::env::temp_dir().join("baker_rust_recipe_bound_test");
+        let path = dir.join("bound.cfg");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            &path,
+            "[*:secrets]\nSHARED=s\n\
+             [dev:db:template]\ntemplate=t\n[dev:db:secrets]\nPASSWORD=dev\n\
+             [prod:db:template]\ntemplate=t\n[prod:db:secrets]\nPASSWORD=prod\n",
+        )
+        .unwrap();
+
+        let settings = Settings::load(false).unwrap();
+        let crypto = Crypto::new(vec![8u8; 32]).with_binding(true);
+        encrypt_recipe_file(path.to_str().unwrap(), &settings, &crypto).unwrap();
+        let recipe = parse(path.to_str().unwrap(), &settings, Some(false)).unwrap();
+        assert!(recipe.instructions[0].secrets.values().all(|v| Crypto::is_bound(v)));
+
+        let mut instructions = recipe.instructions.clone();
+        decrypt_secrets(&mut instructions, Some(&crypto), false, &providers()).unwrap();
+        assert_eq!(instructions[1].variables.get("password").unwrap(), "prod");
+        assert_eq!(instructions[1].variables.get("shared").unwrap(), "s");
+
+        // A dev ciphertext copied into prod does not decrypt there.
+        let mut swapped = recipe.instructions.clone();
+        let dev = swapped[0].secrets["password"].clone();
+        swapped[1].secrets.insert("password".into(), dev);
+        assert!(decrypt_secrets(&mut swapped, Some(&crypto), false, &providers()).is_err());
+
+        let mut unbound = recipe.instructions.clone();
+        let plain = crypto.encrypt_with(None, "old").unwrap();
+        unbound[1].secrets.insert("password".into(), plain);
+        assert!(decrypt_secrets(&mut unbound.clone(), Some(&crypto), false, &providers()).is_err());
+        let lenient = Crypto::new(vec![8u8; 32]);
+        decrypt_secrets(&mut unbound, Some(&lenient), false, &providers()).unwrap();
+        assert_eq!(unbound[1].variables.get("password").unwrap(), "old");
+
+        // A global ciphertext pasted into an instruction's own section does not decrypt there.
+        fs::write(
+            &path,
+            "[*:secrets]\nPASSWORD=global\n\
+             [dev:db:template]\ntemplate=t\n\
+             [prod:db:template]\ntemplate=t\n[prod:db:secrets]\nPASSWORD=prod\n",
+        )
+        .unwrap();
+        encrypt_recipe_file(path.to_str().unwrap(), &settings, &crypto).unwrap();
+        let mut recipe = parse(path.to_str().unwrap(), &settings, Some(false)).unwrap();
+        decrypt_secrets(&mut recipe.instructions, Some(&crypto), false, &providers()).unwrap();
+        assert_eq!(recipe.instructions[0].variables.get("password").unwrap(), "global");
+        let content = fs::read_to_string(&path).unwrap();
+        let values: Vec<&str> = content.lines().filter(|l| l.starts_with("PASSWORD")).collect();
+        fs::write(&path, content.replacen(values[1], values[0], 1)).unwrap();
+        let mut pasted = parse(path.to_str().unwrap(), &settings, Some(false)).unwrap();
+        let global = &recipe.instructions[0].secrets["password"];
+        assert_eq!(&pasted.instructions[1].secrets["password"], global);
+        let pasted = decrypt_secrets(&mut pasted.instructions, Some(&crypto), false, &providers());
+        assert!(pasted.is_err());
+    }
+
+    #[test]
+    fn provider_secrets_are_kept_and_resolved_without_key() {
+        let dir = std::env::temp_dir().join("baker_rust_recipe_provider_test");
+        let path = dir.join("providers.cfg");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            &path,
+            "[app:template]\ntemplate=t\n[app:secrets]\nA=env:BAKER_RUST_MISSING:-fallback\n",
+        )
+        .unwrap();
+
+        let settings = Settings::load(false).unwrap();
+        let crypto = Crypto::new(vec![6u8; 32]);
+        let summary = encrypt_recipe_file(path.to_str().unwrap(), &settings, &crypto).unwrap();
+        assert_eq!((summary.encrypted, summary.providers), (0, 1));
+
+        let mut recipe = parse(path.to_str().unwrap(), &settings, Some(false)).unwrap();
+        decrypt_secrets(&mut recipe.instructions, None, false, &providers()).unwrap();
+        assert_eq!(recipe.instructions[0].variables.get("a").unwrap(), "fallback");
+    }
+
+    #[test]
+    fn encrypt_recipe_file_skips_existing_ciphertext() {
+        let dir = std::env::temp_dir().join("baker_rust_recipe_idempotent_test");
+        let path = dir.join("twice.cfg");
+        fs::create_dir_all(&dir).unwrap();
+        let foreign = Crypto::new(vec![9u8; 32]).encrypt_with(None, "other key").unwrap();
+        fs::write(
+            &path,
+            format!("[app:template]\ntemplate=t\n[app:secrets]\nA=one\nB=two\nC={}\n", foreign),
+        )
+        .unwrap();
+
+        let settings = Settings::load(false).unwrap();
+        let crypto = Crypto::new(vec![8u8; 32]);
+        let first = encrypt_recipe_file(path.to_str().unwrap(), &settings, &crypto).unwrap();
+        assert_eq!((first.encrypted, first.skipped, first.foreign), (2, 1, 1));
+        let after_first = fs::read_to_string(&path).unwrap();
+
+        let second = encrypt_recipe_file(path.to_str().unwrap(), &settings, &crypto).unwrap();
+        assert_eq!((second.encrypted, second.skipped, second.foreign), (0, 3, 1));
+        assert_eq!(fs::read_to_string(&path).unwrap(), after_first);
+    }
+
+    #[test]
+    fn toml_recipes_parse_and_encrypt_in_place() {
+        let dir = std::env::temp_dir().join("baker_rust_recipe_toml_test");
+        let path = dir.join("recipe.toml");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            &path,
+            "[variables]\nDOMAIN = \"example.com\"\n\n\
+             [dev.app.template]\ntemplate = \"t\"\npath = \"out\"\nafter = [\"db\"]\n\n\
+             [dev.app.variables]\nPORT = 8080\n\n\
+             [dev.app.secrets]\n# keep me\nPASSWORD = \"hunter2\" # inline\n\n\
+             [dev.db.template]\ntemplate = \"db\"\n",
+        )
+        .unwrap();
+
+        let settings = Settings::load(false).unwrap();
+        let recipe = parse(path.to_str().unwrap(), &settings, Some(false)).unwrap();
+        let names: Vec<&str> = recipe.instructions.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, vec!["dev:db", "dev:app"]);
+        let app = &recipe.instructions[1];
+        assert_eq!(app.template.path.as_deref(), Some("out"));
+        assert_eq!(app.variables.get("port").unwrap(), "8080");
+        assert_eq!(app.variables.get("domain").unwrap(), "example.com");
+        assert_eq!(app.secrets.get("password").unwrap(), "hunter2");
+
+        let crypto = Crypto::new(vec![3u8; 32]);
+        encrypt_recipe_file(path.to_str().unwrap(), &settings, &crypto).unwrap();
+        let rewritten = fs::read_to_string(&path).unwrap();
+        assert!(rewritten.contains("# keep me\nPASSWORD = "));
+        assert!(rewritten.contains(" # inline"));
+        assert!(!rewritten.contains("hunter2"));
+
+        let mut recipe = parse(path.to_str().unwrap(), &settings, Some(false)).unwrap();
+        decrypt_secrets(&mut recipe.instructions, Some(&crypto), false, &providers()).unwrap();
+        assert_eq!(recipe.instructions[1].variables.get("password").unwrap(), "hunter2");
+
+        fs::write(&path, "[app.template]\ntemplate = \"t\"\nbogus = 1\n").unwrap();
+        let err = parse(path.to_str().unwrap(), &settings, Some(false)).unwrap_err();
+        let err = err.downcast_ref::<RecipeError>().expect("RecipeError");
+        assert_eq!(err.line, 3);
+    }
+
+    #[test]
+    fn yaml_and_json_recipes_match_ini() {
+        let dir = std::env::temp_dir().join("baker_rust_recipe_tree_test");
+        fs::create_dir_all(&dir).unwrap();
+        let yaml = dir.join("recipe.yaml");
+        fs::write(
+            &yaml,
+            "dev:\n  app:\n    template:\n      template: t\n      path: out\n    variables:\n      \
+             PORT: 8080\n    secrets:\n      PASSWORD: hunter2\n",
+        )
+        .unwrap();
+        let json = dir.join("recipe.json");
+        fs::write(
+            &json,
+            "{\"dev\": {\"app\": {\"template\": {\"template\": \"t\", \"path\": \"out\"},\n\
+             \"variables\": {\"PORT\": 8080}, \"secrets\": {\"PASSWORD\": \"hunter2\"}}}}\n",
+        )
+        .unwrap();
+
+        let settings = Settings::load(false).unwrap();
+        let crypto = Crypto::new(vec![5u8; 32]);
+        for path in [yaml, json] {
+            let path = path.to_str().unwrap();
+            let recipe = parse(path, &settings, Some(false)).unwrap();
+            assert_eq!(recipe.environments(), vec!["dev"]);
+            let app = &recipe.instructions[0];
+            assert_eq!(app.name, "dev:app");
+            assert_eq!(app.template.path.as_deref(), Some("out"));
+            assert_eq!(app.variables.get("port").unwrap(), "8080");
+
+            encrypt_recipe_file(path, &settings, &crypto).unwrap();
+            assert!(!fs::read_to_string(path).unwrap().contains("hunter2"));
+            let mut recipe = parse(path, &settings, Some(false)).unwrap();
+            decrypt_secrets(&mut recipe.instructions, Some(&crypto), false, &providers()).unwrap();
+            assert_eq!(recipe.instructions[0].variables.get("password").unwrap(), "hunter2");
+        }
+
+        // A pulled recipe saved under its version takes its format from RECIPE_EXT.
+        let pulled = dir.join("v1.0?at=v1");
+        fs::write(&pulled, "dev:\n  app:\n    template:\n      template: t\n").unwrap();
+        let mut settings = settings.clone();
+        settings.recipe_ext = "yml".to_string();
+        settings.recipe_format = Some(RecipeFormat::pulled(&settings).unwrap().name().into());
+        let recipe = parse(pulled.to_str().unwrap(), &settings, Some(false)).unwrap();
+        assert_eq!(recipe.instructions[0].name, "dev:app");
+    }
 }
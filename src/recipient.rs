@@ -0,0 +1,167 @@
+use crate::sensitive::{SecretBytes, SecretString};
+use age::secrecy::ExposeSecret;
+use age::x25519;
+use age::DecryptError;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::error::Error;
+use std::fmt;
+use std::io::{Read, Write};
+use std::iter;
+
+/// First part of recipient ciphertexts: `age\<base64 of a binary age file>`.
+const PREFIX: &str = "age\\";
+const AGE_MAGIC: &[u8] = b"age-encryption.org/v1\n";
+const SECRET_KEY_PREFIX: &str = "AGE-SECRET-KEY-1";
+
+/// An age X25519 public key secrets can be encrypted to, written `age1...`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recipient(x25519::Recipient);
+
+impl Recipient {
+    pub fn parse(value: &str) -> Result<Self, Box<dyn Error>> {
+        let value = value.trim();
+        value.parse().map(Recipient).map_err(|e| {
+            format!("Recipient '{}' must be an age public key (age1...): {}", value, e).into()
+        })
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A member's private key, decrypting secrets encrypted to its `Recipient`. Stored as an
+/// age identity file, so `age -d -i` reads it too.
+pub struct Identity {
+    secret: x25519::Identity,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Identity {
+            secret: x25519::Identity::generate(),
+        }
+    }
+
+    pub fn recipient(&self) -> Recipient {
+        Recipient(self.secret.to_public())
+    }
+
+    pub fn serialize(&self) -> SecretString {
+        let secret = self.secret.to_string();
+        let mut content = SecretString::new(String::with_capacity(256));
+        content.push_str(&format!("# public key: {}\n", self.recipient()));
+        content.push_str(secret.expose_secret());
+        content.push('\n');
+        content
+    }
+
+    pub fn parse(content: &str) -> Result<Self, Box<dyn Error>> {
+        let secret = content
+            .lines()
+            .map(str::trim)
+            .find(|l| l.starts_with(SECRET_KEY_PREFIX))
+            .ok_or_else(|| format!("Identity file has no {} line", SECRET_KEY_PREFIX))?;
+        let secret = secret
+            .parse()
+            .map_err(|e| format!("Identity secret is invalid: {}", e))?;
+        Ok(Identity { secret })
+    }
+
+    /// Decrypts `value`; `aad` must match the associated data it was encrypted with.
+    pub fn decrypt(&self, value: &str, aad: &[u8]) -> Result<SecretString, Box<dyn Error>> {
+        let data = decode(value).ok_or("Encryption is corrupted: not a recipient ciphertext")?;
+        let decryptor = age::Decryptor::new_buffered(data.as_slice())
+            .map_err(|e| format!("Encryption is corrupted: {}", e))?;
+        let mut reader = decryptor
+            .decrypt(iter::once(&self.secret as &dyn age::Identity))
+            .map_err(|e| match e {
+                DecryptError::NoMatchingKeys => {
+                    "This identity is not a recipient of the secret".to_string()
+                }
+                e => format!("decrypt error: {}", e),
+            })?;
+        // Sized up front so the plaintext is never reallocated (and copied) while read.
+        let mut payload = SecretBytes::new(Vec::with_capacity(data.len()));
+        reader
+            .read_to_end(&mut payload)
+            .map_err(|e| format!("decrypt error: {}", e))?;
+        let header = framing(aad);
+        if !payload.starts_with(&header) {
+            return Err("decrypt error: secret is bound to another instruction or name".into());
+        }
+        payload.drain(..header.len());
+        SecretString::from_utf8(payload)
+    }
+}
+
+/// Encrypts `raw` as an age file that any of `recipients` can decrypt with their identity,
+/// given the same associated data `aad`. age has no associated data, so `aad` and a line
+/// break are written before `raw` in the encrypted payload and checked on decryption.
+pub fn encrypt(recipients: &[Recipient], raw: &str, aad: &[u8]) -> Result<String, Box<dyn Error>> {
+    if recipients.is_empty() {
+        return Err("At least one recipient is required".into());
+    }
+    let encryptor =
+        age::Encryptor::with_recipients(recipients.iter().map(|r| &r.0 as &dyn age::Recipient))
+            .map_err(|e| format!("encrypt error: {}", e))?;
+    let mut output = Vec::new();
+    let mut writer = encryptor.wrap_output(&mut output)?;
+    writer.write_all(&framing(aad))?;
+    writer.write_all(raw.as_bytes())?;
+    writer.finish()?;
+    Ok(format!("{}{}", PREFIX, STANDARD.encode(output)))
+}
+
+/// Whether `value` has the shape produced by `encrypt`.
+pub fn is_ciphertext(value: &str) -> bool {
+    decode(value).is_some_and(|data| data.starts_with(AGE_MAGIC))
+}
+
+fn decode(value: &str) -> Option<Vec<u8>> {
+    STANDARD.decode(value.strip_prefix(PREFIX)?).ok()
+}
+
+fn framing(aad: &[u8]) -> Vec<u8> {
+    let mut header = aad.to_vec();
+    header.push(b'\n');
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_recipient_decrypts_and_others_cannot() {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let eve = Identity::generate();
+        let recipients = vec![alice.recipient(), bob.recipient()];
+        let value = encrypt(&recipients, "team secret", b"").unwrap();
+
+        assert!(is_ciphertext(&value));
+        assert_eq!(alice.decrypt(&value, b"").unwrap(), "team secret");
+        assert_eq!(bob.decrypt(&value, b"").unwrap(), "team secret");
+        assert!(eve.decrypt(&value, b"").is_err());
+        assert!(alice.decrypt(&value, b"other").is_err());
+        let bound = encrypt(&recipients, "team secret", b"other").unwrap();
+        assert!(alice.decrypt(&bound, b"").is_err());
+        assert_eq!(alice.decrypt(&bound, b"other").unwrap(), "team secret");
+
+        // Plain age files: the payload is the framing line and the secret.
+        let data = decode(&value).unwrap();
+        let plain = age::decrypt(&alice.secret, &data).unwrap();
+        assert_eq!(plain, b"\nteam secret");
+
+        let restored = Identity::parse(&alice.serialize()).unwrap();
+        assert_eq!(restored.decrypt(&value, b"").unwrap(), "team secret");
+        let text = alice.recipient().to_string();
+        assert!(text.starts_with("age1"));
+        assert_eq!(Recipient::parse(&text).unwrap(), alice.recipient());
+        assert!(Recipient::parse("ssh-ed25519 AAAA").is_err());
+    }
+}
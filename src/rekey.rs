@@ -0,0 +1,426 @@
+use crate::recipe::{parse, rewritten_secrets, EncryptionKeys};
+use crate::recipient;
+use crate::secret::{Crypto, EncryptTarget};
+use crate::settings::Settings;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default)]
+pub struct RekeySummary {
+    /// Recipe files that hold at least one encrypted secret.
+    pub files: Vec<String>,
+    pub secrets: usize,
+    /// Plain secrets (left for `encrypt --file`) and provider references.
+    pub skipped: usize,
+    /// Secrets encrypted with another key of the key ring or to recipients.
+    pub other_keys: usize,
+    /// Files found in directories that do not parse as recipes.
+    pub ignored: Vec<String>,
+}
+
+/// Re-encrypts the secrets of key `key_id` (the default key when `None`) in the recipes
+/// under `paths` from `old` to `new`. Nothing is written unless all of them decrypt with `old`;
+/// `before_write` runs first (to save the new key) and stops the rotation when it fails.
+pub fn rekey(
+    paths: &[String],
+    settings: &Settings,
+    old: &Crypto,
+    new: &Crypto,
+    key_id: Option<&str>,
+    dry_run: bool,
+    before_write: &mut dyn FnMut() -> Result<(), Box<dyn Error>>,
+) -> Result<RekeySummary, Box<dyn Error>> {
+    let mut summary = RekeySummary::default();
+    let mut outputs = Vec::new();
+    for (file, found) in recipe_files(paths, settings)? {
+        let file = file.to_string_lossy().to_string();
+        if found && parse(&file, settings, Some(true)).is_err() {
+            summary.ignored.push(file);
+            continue;
+        }
+        let mut secrets = 0;
+        let output = rewritten_secrets(&file, settings, &mut |name, key, value| {
+            if !Crypto::is_ciphertext(value) {
+                summary.skipped += 1;
+                return Ok(None);
+            }
+            if Crypto::key_id(value) != key_id || recipient::is_ciphertext(Crypto::unbound(value))
+            {
+                summary.other_keys += 1;
+                return Ok(None);
+            }
+            let plain = old.decrypt_secret(value, name, key).map_err(|e| {
+                format!("{}: cannot decrypt [{}] {}: {}", file, name, key, e)
+            })?;
+            secrets += 1;
+            // Bound secrets stay bound.
+            Ok(Some(if Crypto::is_bound(value) {
+                new.encrypt_bound(EncryptTarget::Key(key_id), &plain, name, key)?
+            } else {
+                new.encrypt_with(key_id, &plain)?
+            }))
+        })?;
+        if let Some(output) = output {
+            summary.secrets += secrets;
+            summary.files.push(file.clone());
+            outputs.push((file, output));
+        }
+    }
+    if !dry_run {
+        before_write()?;
+        replace_all(&outputs)?;
+    }
+    Ok(summary)
+}
+
+#[derive(Debug, Default)]
+pub struct BindSummary {
+    /// Recipe files with at least one secret to bind.
+    pub files: Vec<String>,
+    pub secrets: usize,
+    /// Secrets that were already bound.
+    pub bound: usize,
+    /// Plain secrets (left for `encrypt --file`) and provider references.
+    pub skipped: usize,
+    /// Files found in directories that do not parse as recipes.
+    pub ignored: Vec<String>,
+}
+
+/// Rewrites the unbound secrets of the recipes under `paths` in the `bound:` form with the
+/// same key. Recipient secrets are encrypted again to the recipients the recipe lists for
+/// them. Nothing is written unless all of them decrypt.
+pub fn bind(
+    paths: &[String],
+    settings: &Settings,
+    crypto: &Crypto,
+    dry_run: bool,
+) -> Result<BindSummary, Box<dyn Error>> {
+    let mut summary = BindSummary::default();
+    let mut outputs = Vec::new();
+    for (file, found) in recipe_files(paths, settings)? {
+        let file = file.to_string_lossy().to_string();
+        let recipe = match parse(&file, settings, Some(true)) {
+            Ok(recipe) => recipe,
+            Err(_) if found => {
+                summary.ignored.push(file);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        let keys = EncryptionKeys::of(&recipe, settings);
+        let mut secrets = 0;
+        let output = rewritten_secrets(&file, settings, &mut |name, key, value| {
+            if !Crypto::is_ciphertext(value) {
+                summary.skipped += 1;
+                return Ok(None);
+            }
+            if Crypto::is_bound(value) {
+                summary.bound += 1;
+                return Ok(None);
+            }
+            let plain = crypto.decrypt(value).map_err(|e| {
+                format!("{}: cannot decrypt [{}] {}: {}", file, name, key, e)
+            })?;
+            let target = if recipient::is_ciphertext(value) {
+                match keys.for_instruction(name) {
+                    target @ EncryptTarget::Recipients(_) => target,
+                    EncryptTarget::Key(_) => {
+                        return Err(format!(
+                            "{}: [{}] {} is encrypted to recipients the recipe does not list",
+                            file, name, key
+                        )
+                        .into())
+                    }
+                }
+            } else {
+                EncryptTarget::Key(Crypto::key_id(value))
+            };
+            secrets += 1;
+            Ok(Some(crypto.encrypt_bound(target, &plain, name, key)?))
+        })?;
+        if let Some(output) = output {
+            summary.secrets += secrets;
+            summary.files.push(file.clone());
+            outputs.push((file, output));
+        }
+    }
+    if !dry_run {
+        replace_all(&outputs)?;
+    }
+    Ok(summary)
+}
+
+#[derive(Debug, Default)]
+pub struct RewrapSummary {
+    /// Recipe files with at least one recipient secret.
+    pub files: Vec<String>,
+    pub secrets: usize,
+    /// Secrets that are not encrypted to recipients.
+    pub skipped: usize,
+    /// Files found in directories that do not parse as recipes.
+    pub ignored: Vec<String>,
+}
+
+/// Encrypts the recipient secrets of the recipes under `paths` again to the recipients each
+/// recipe lists now, so added members can decrypt them and removed ones cannot decrypt the
+/// new values. Bound secrets stay bound. Nothing is written unless all of them decrypt with
+/// the identity.
+pub fn rewrap(
+    paths: &[String],
+    settings: &Settings,
+    crypto: &Crypto,
+    dry_run: bool,
+) -> Result<RewrapSummary, Box<dyn Error>> {
+    let mut summary = RewrapSummary::default();
+    let mut outputs = Vec::new();
+    for (file, found) in recipe_files(paths, settings)? {
+        let file = file.to_string_lossy().to_string();
+        let recipe = match parse(&file, settings, Some(true)) {
+            Ok(recipe) => recipe,
+            Err(_) if found => {
+                summary.ignored.push(file);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        let keys = EncryptionKeys::of(&recipe, settings);
+        let mut secrets = 0;
+        let output = rewritten_secrets(&file, settings, &mut |name, key, value| {
+            if !recipient::is_ciphertext(Crypto::unbound(value)) {
+                summary.skipped += 1;
+                return Ok(None);
+            }
+            let target = match keys.for_instruction(name) {
+                target @ EncryptTarget::Recipients(_) => target,
+                EncryptTarget::Key(_) => {
+                    return Err(format!(
+                        "{}: [{}] {} is encrypted to recipients the recipe does not list",
+                        file, name, key
+                    )
+                    .into())
+                }
+            };
+            let plain = crypto.decrypt_secret(value, name, key).map_err(|e| {
+                format!("{}: cannot decrypt [{}] {}: {}", file, name, key, e)
+            })?;
+            secrets += 1;
+            Ok(Some(if Crypto::is_bound(value) {
+                crypto.encrypt_bound(target, &plain, name, key)?
+            } else {
+                crypto.encrypt_for(target, &plain)?
+            }))
+        })?;
+        if let Some(output) = output {
+            summary.secrets += secrets;
+            summary.files.push(file.clone());
+            outputs.push((file, output));
+        }
+    }
+    if !dry_run {
+        replace_all(&outputs)?;
+    }
+    Ok(summary)
+}
+
+/// Stages every file next to its target first, so a failed write leaves all recipes intact.
+fn replace_all(outputs: &[(String, String)]) -> Result<(), Box<dyn Error>> {
+    let staged: Vec<PathBuf> = outputs
+        .iter()
+        .map(|(file, _)| PathBuf::from(format!("{}.rekey", file)))
+        .collect();
+    for ((_, output), tmp) in outputs.iter().zip(&staged) {
+        if let Err(err) = fs::write(tmp, output) {
+            for tmp in &staged {
+                let _ = fs::remove_file(tmp);
+            }
+            return Err(format!("Cannot write {}: {}", tmp.display(), err).into());
+        }
+    }
+    for ((file, _), tmp) in outputs.iter().zip(&staged) {
+        fs::rename(tmp, file)?;
+    }
+    Ok(())
+}
+
+/// Files given directly plus recipe files found recursively in directories, the latter
+/// flagged: only those may turn out not to be recipes (e.g. CI workflows in YAML).
+fn recipe_files(
+    paths: &[String],
+    settings: &Settings,
+) -> Result<Vec<(PathBuf, bool)>, Box<dyn Error>> {
+    let mut files = Vec::new();
+    for path in paths {
+        let path = Path::new(path);
+        if path.is_dir() {
+            collect_recipes(path, settings, &mut files)?;
+        } else if path.is_file() {
+            files.push((path.to_path_buf(), false));
+        } else {
+            return Err(format!("Recipe path '{}' does not exist", path.display()).into());
+        }
+    }
+    Ok(files)
+}
+
+fn collect_recipes(
+    dir: &Path,
+    settings: &Settings,
+    files: &mut Vec<(PathBuf, bool)>,
+) -> Result<(), Box<dyn Error>> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            collect_recipes(&path, settings, files)?;
+        } else if is_recipe(&path, settings) {
+            files.push((path, true));
+        }
+    }
+    Ok(())
+}
+
+fn is_recipe(path: &Path, settings: &Settings) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => {
+            ext == settings.recipe_ext || matches!(ext, "toml" | "yaml" | "yml" | "json")
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recipe::{decrypt_recipe_file, encrypt_recipe_file};
+    use crate::recipient::Identity;
+
+    #[test]
+    fn rekey_rewrites_all_or_nothing() {
+        let dir = std::env::temp_dir().join("baker_rust_rekey_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        let first = dir.join("a.cfg");
+        let second = dir.join("nested").join("b.toml");
+        fs::write(&first, "[app:template]\ntemplate=t\n[app:secrets]\nA=one\n").unwrap();
+        fs::write(&second, "[db.template]\ntemplate = \"t\"\n[db.secrets]\nB = \"two\"\n").unwrap();
+        let workflow = dir.join("nested").join("ci.yml");
+        fs::write(&workflow, "on: push\njobs:\n  test:\n    steps: [1, 2]\n").unwrap();
+
+        let settings = Settings::load(false).unwrap();
+        let old = Crypto::new(vec![1u8; 32]);
+        let new = Crypto::new(vec![2u8; 32]);
+        for file in [&first, &second] {
+            encrypt_recipe_file(file.to_str().unwrap(), &settings, &old).unwrap();
+        }
+        let paths = vec![dir.to_string_lossy().to_string()];
+
+        let before = fs::read_to_string(&first).unwrap();
+        let dry = rekey(&paths, &settings, &old, &new, None, true, &mut || Ok(())).unwrap();
+        assert_eq!((dry.files.len(), dry.secrets), (2, 2));
+        assert_eq!(fs::read_to_string(&first).unwrap(), before);
+
+        // The second file does not decrypt with `old`, so the first must stay untouched.
+        let plain_b = "[db.template]\ntemplate = \"t\"\n[db.secrets]\nB = \"two\"\n";
+        fs::write(&second, plain_b).unwrap();
+        encrypt_recipe_file(second.to_str().unwrap(), &settings, &new).unwrap();
+        assert!(rekey(&paths, &settings, &old, &new, None, false, &mut || Ok(())).is_err());
+        assert_eq!(fs::read_to_string(&first).unwrap(), before);
+
+        fs::write(&second, plain_b).unwrap();
+        encrypt_recipe_file(second.to_str().unwrap(), &settings, &old).unwrap();
+        // Nothing is written when the new key cannot be saved first.
+        let mut saved = false;
+        let failed = rekey(&paths, &settings, &old, &new, None, false, &mut || {
+            Err("no space".into())
+        });
+        assert!(failed.is_err());
+        assert_eq!(fs::read_to_string(&first).unwrap(), before);
+        let summary = rekey(&paths, &settings, &old, &new, None, false, &mut || {
+            saved = true;
+            Ok(())
+        })
+        .unwrap();
+        assert!(saved);
+        assert_eq!(summary.ignored, vec![workflow.to_string_lossy().to_string()]);
+        let secrets = decrypt_recipe_file(second.to_str().unwrap(), &settings, &new).unwrap();
+        assert_eq!(secrets, vec![("db".into(), "B".into(), "two".into())]);
+        let secrets = decrypt_recipe_file(first.to_str().unwrap(), &settings, &new).unwrap();
+        assert_eq!(secrets, vec![("app".into(), "A".into(), "one".into())]);
+    }
+
+    #[test]
+    fn bind_upgrades_unbound_secrets_with_their_key() {
+        let dir = std::env::temp_dir().join("baker_rust_bind_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("a.cfg");
+        let recipe = "key = prod\n[app:template]\ntemplate=t\n[app:secrets]\nA=one\nB=env:B\n";
+        fs::write(&file, recipe).unwrap();
+
+        let settings = Settings::load(false).unwrap();
+        let mut crypto = Crypto::named("prod", vec![3u8; 32]);
+        crypto.insert("dev", vec![4u8; 32]);
+        let path = file.to_str().unwrap();
+        encrypt_recipe_file(path, &settings, &Crypto::named("prod", vec![3u8; 32])).unwrap();
+        let paths = vec![path.to_string()];
+
+        let summary = bind(&paths, &settings, &crypto, false).unwrap();
+        assert_eq!((summary.secrets, summary.bound, summary.skipped), (1, 0, 1));
+        let recipe = parse(path, &settings, Some(false)).unwrap();
+        let value = &recipe.instructions[0].secrets["a"];
+        assert!(Crypto::is_bound(value));
+        assert_eq!(Crypto::key_id(value), Some("prod"));
+        assert_eq!(crypto.decrypt_secret(value, "app", "A").unwrap(), "one");
+        assert!(crypto.decrypt_secret(value, "other", "A").is_err());
+
+        let again = bind(&paths, &settings, &crypto, false).unwrap();
+        assert_eq!((again.secrets, again.bound), (0, 1));
+    }
+
+    #[test]
+    fn rewrap_follows_the_listed_recipients() {
+        let dir = std::env::temp_dir().join("baker_rust_rewrap_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("team.cfg");
+        let path = file.to_str().unwrap();
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let copy = |identity: &Identity| Identity::parse(&identity.serialize()).unwrap();
+        let recipe = |recipients: String| {
+            format!(
+                "recipients = {}\n[app:template]\ntemplate=t\n[app:secrets]\nA=one\nB=env:B\n",
+                recipients
+            )
+        };
+        fs::write(&file, recipe(alice.recipient().to_string())).unwrap();
+        let settings = Settings::load(false).unwrap();
+        let as_alice = Crypto::with_identity(copy(&alice));
+        encrypt_recipe_file(path, &settings, &as_alice).unwrap();
+        let secret = |path: &str| {
+            let recipe = parse(path, &settings, Some(false)).unwrap();
+            recipe.instructions[0].secrets["a"].clone()
+        };
+
+        // Adding bob: encrypt --file keeps the value, rewrap encrypts it to both.
+        let both = format!("{}, {}", alice.recipient(), bob.recipient());
+        let content = fs::read_to_string(&file).unwrap();
+        fs::write(&file, content.replacen(&alice.recipient().to_string(), &both, 1)).unwrap();
+        assert_eq!(encrypt_recipe_file(path, &settings, &as_alice).unwrap().encrypted, 0);
+        assert!(Crypto::with_identity(copy(&bob)).decrypt(&secret(path)).is_err());
+        let paths = vec![path.to_string()];
+        let summary = rewrap(&paths, &settings, &as_alice, false).unwrap();
+        assert_eq!((summary.secrets, summary.skipped), (1, 1));
+        assert_eq!(Crypto::with_identity(copy(&bob)).decrypt(&secret(path)).unwrap(), "one");
+
+        // Removing alice: the new value is only bob's.
+        let content = fs::read_to_string(&file).unwrap();
+        fs::write(&file, content.replacen(&both, &bob.recipient().to_string(), 1)).unwrap();
+        rewrap(&paths, &settings, &Crypto::with_identity(copy(&bob)), false).unwrap();
+        assert!(as_alice.decrypt(&secret(path)).is_err());
+        assert_eq!(Crypto::with_identity(bob).decrypt(&secret(path)).unwrap(), "one");
+    }
+}
@@ -90,7 +90,7 @@ impl<'a> Repository<'a> {
             .repository
             .as_ref()
             .ok_or("REPOSITORY must be set")?;
-        let ext = "cfg";
+        let ext = &self.settings.recipe_ext;
         let rtype = self.settings.repository_type.as_ref().unwrap();
         let pattern = match rtype.as_str() {
             "custom" => self
@@ -0,0 +1,90 @@
+use std::error::Error;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use zeroize::Zeroize;
+
+/// Key material or a secret plaintext: its memory is zeroed when dropped and `Debug`
+/// never shows it.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Sensitive<T: Zeroize>(T);
+
+pub type SecretString = Sensitive<String>;
+pub type SecretBytes = Sensitive<Vec<u8>>;
+
+impl<T: Zeroize> Sensitive<T> {
+    pub fn new(value: T) -> Self {
+        Sensitive(value)
+    }
+}
+
+impl SecretString {
+    /// The text of decrypted `bytes`; they are wiped if they are not UTF-8.
+    pub fn from_utf8(mut bytes: SecretBytes) -> Result<Self, Box<dyn Error>> {
+        std::str::from_utf8(&bytes)?;
+        Ok(Sensitive(String::from_utf8(std::mem::take(&mut bytes.0))?))
+    }
+}
+
+impl<T: Zeroize> Drop for Sensitive<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl<T: Zeroize> Deref for Sensitive<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Zeroize> DerefMut for Sensitive<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Zeroize> fmt::Debug for Sensitive<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl<T: Zeroize> From<T> for Sensitive<T> {
+    fn from(value: T) -> Self {
+        Sensitive(value)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(value: &str) -> Self {
+        Sensitive(value.to_string())
+    }
+}
+
+impl PartialEq<str> for SecretString {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for SecretString {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_hides_the_value() {
+        let secret = SecretString::from("hunter2");
+        assert_eq!(format!("{:?}", secret), "<redacted>");
+        assert_eq!(format!("{:?}", Some(SecretBytes::from(vec![1u8]))), "Some(<redacted>)");
+        assert_eq!(secret, "hunter2");
+        assert_eq!(secret.len(), 7);
+    }
+}
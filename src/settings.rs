@@ -8,9 +8,13 @@ pub const VERSION: &str = "0.1.0";
 
 #[derive(Clone, Debug)]
 pub struct Settings {
+    /// Write secrets bound to their instruction and key, and refuse unbound ones on `run`.
+    pub bind_secrets: bool,
     pub debug: bool,
     pub encoding: String,
     pub recipe_case_sensitive: bool,
+    pub recipe_format: Option<String>,
+    pub recipe_ext: String,
     pub repository: Option<String>,
     pub repository_type: Option<String>,
     pub repository_auth: Option<String>,
@@ -19,6 +23,21 @@ pub struct Settings {
     pub storage_recipe_index: PathBuf,
     pub storage_recipe_meta: PathBuf,
     pub storage_key_path: PathBuf,
+    pub storage_keys: PathBuf,
+    /// Default key file given with `--key-file`; wins over `BAKER_KEY` and `BAKER_KEY_FILE`.
+    pub key_file: Option<PathBuf>,
+    /// Private X25519 identity decrypting secrets encrypted to recipients.
+    pub storage_identity: PathBuf,
+    /// Default recipients (age `age1...` public keys) for `encrypt --file`.
+    pub recipients: Vec<String>,
+    /// Refuse key files readable by group or others instead of only warning.
+    pub strict_key_permissions: bool,
+    /// `legacy` or `v2`: the ciphertext form secrets are written in.
+    pub secret_format: String,
+    /// Seconds a `cmd:` secret provider may run.
+    pub secret_command_timeout: u64,
+    /// Secret providers recipes may use (`env`, `file`, `cmd`).
+    pub secret_providers: Vec<String>,
     pub storage_templates: PathBuf,
     pub template_ext: Option<String>,
     pub custom_overrides: HashMap<String, String>,
@@ -30,9 +49,12 @@ impl Settings {
         let baker_dir = home.join(".baker");
         let bakerc_path = home.join(".bakerc");
         let mut values = Settings {
+            bind_secrets: false,
             debug: verbose,
             encoding: "utf-8".to_string(),
             recipe_case_sensitive: false,
+            recipe_format: None,
+            recipe_ext: "cfg".to_string(),
             repository: None,
             repository_type: None,
             repository_auth: None,
@@ -41,6 +63,14 @@ impl Settings {
             storage_recipe_index: baker_dir.join("index"),
             storage_recipe_meta: baker_dir.join("meta"),
             storage_key_path: baker_dir.join("baker.key"),
+            storage_keys: baker_dir.join("keys"),
+            key_file: None,
+            storage_identity: baker_dir.join("identity.key"),
+            recipients: Vec::new(),
+            strict_key_permissions: true,
+            secret_format: "legacy".to_string(),
+            secret_command_timeout: 30,
+            secret_providers: vec!["env".to_string()],
             storage_templates: baker_dir.join("templates"),
             template_ext: Some("tpl".to_string()),
             custom_overrides: HashMap::new(),
@@ -58,21 +88,37 @@ impl Settings {
                     let val = value.trim();
                     values.custom_overrides.insert(key.clone(), val.to_string());
                     match key.as_str() {
+                        "BIND_SECRETS" => values.bind_secrets = parse_bool(val).unwrap_or(false),
                         "DEBUG" => values.debug = parse_bool(val).unwrap_or(verbose),
                         "ENCODING" => values.encoding = val.to_string(),
                         "RECIPE_CASE_SENSITIVE" => {
                             values.recipe_case_sensitive = parse_bool(val).unwrap_or(false)
                         }
+                        "RECIPE_FORMAT" => values.recipe_format = Some(val.to_lowercase()),
+                        "RECIPE_EXT" => values.recipe_ext = val.trim_start_matches('.').to_string(),
+                        "RECIPIENTS" => values.recipients = split_list(val),
                         "REPOSITORY" => values.repository = Some(val.to_string()),
                         "REPOSITORY_TYPE" => values.repository_type = Some(val.to_string()),
                         "REPOSITORY_AUTH" => values.repository_auth = Some(val.to_string()),
                         "REPOSITORY_CUSTOM_PATTERN" => {
                             values.repository_custom_pattern = Some(val.to_string())
                         }
+                        "SECRET_FORMAT" => values.secret_format = val.to_lowercase(),
+                        "SECRET_COMMAND_TIMEOUT" => {
+                            values.secret_command_timeout = val.parse().unwrap_or(30)
+                        }
+                        "SECRET_PROVIDERS" => {
+                            values.secret_providers = split_list(&val.to_lowercase())
+                        }
                         "STORAGE_RECIPE" => values.storage_recipe = PathBuf::from(val),
                         "STORAGE_RECIPE_INDEX" => values.storage_recipe_index = PathBuf::from(val),
                         "STORAGE_RECIPE_META" => values.storage_recipe_meta = PathBuf::from(val),
                         "STORAGE_KEY_PATH" => values.storage_key_path = PathBuf::from(val),
+                        "STORAGE_IDENTITY" => values.storage_identity = PathBuf::from(val),
+                        "STORAGE_KEYS" => values.storage_keys = PathBuf::from(val),
+                        "STRICT_KEY_PERMISSIONS" => {
+                            values.strict_key_permissions = parse_bool(val).unwrap_or(true)
+                        }
                         "STORAGE_TEMPLATES" => values.storage_templates = PathBuf::from(val),
                         "TEMPLATE_EXT" => {
                             values.template_ext = match val.to_lowercase().as_str() {
@@ -94,17 +140,33 @@ impl Settings {
             return self
                 .custom_overrides
                 .iter()
-                .map(|(k, v)| (k.clone(), v.clone()))
+                .map(|(k, v)| (k.clone(), redacted(k, v)))
                 .collect();
         }
 
         vec![
+            ("BIND_SECRETS".to_string(), self.bind_secrets.to_string()),
             ("DEBUG".to_string(), self.debug.to_string()),
             ("ENCODING".to_string(), self.encoding.clone()),
             (
                 "RECIPE_CASE_SENSITIVE".to_string(),
                 self.recipe_case_sensitive.to_string(),
             ),
+            (
+                "RECIPE_FORMAT".to_string(),
+                self.recipe_format
+                    .clone()
+                    .unwrap_or_else(|| "None".to_string()),
+            ),
+            ("RECIPE_EXT".to_string(), self.recipe_ext.clone()),
+            (
+                "RECIPIENTS".to_string(),
+                if self.recipients.is_empty() {
+                    "None".to_string()
+                } else {
+                    self.recipients.join(", ")
+                },
+            ),
             (
                 "REPOSITORY".to_string(),
                 self.repository.clone().unwrap_or_else(|| "None".to_string()),
@@ -118,8 +180,8 @@ impl Settings {
             (
                 "REPOSITORY_AUTH".to_string(),
                 self.repository_auth
-                    .clone()
-                    .unwrap_or_else(|| "None".to_string()),
+                    .as_deref()
+                    .map_or_else(|| "None".to_string(), |v| redacted("REPOSITORY_AUTH", v)),
             ),
             (
                 "REPOSITORY_CUSTOM_PATTERN".to_string(),
@@ -127,6 +189,19 @@ impl Settings {
                     .clone()
                     .unwrap_or_else(|| "None".to_string()),
             ),
+            (
+                "SECRET_COMMAND_TIMEOUT".to_string(),
+                self.secret_command_timeout.to_string(),
+            ),
+            ("SECRET_FORMAT".to_string(), self.secret_format.clone()),
+            (
+                "SECRET_PROVIDERS".to_string(),
+                if self.secret_providers.is_empty() {
+                    "None".to_string()
+                } else {
+                    self.secret_providers.join(", ")
+                },
+            ),
             (
                 "STORAGE_RECIPE".to_string(),
                 self.storage_recipe.display().to_string(),
@@ -143,10 +218,22 @@ impl Settings {
                 "STORAGE_KEY_PATH".to_string(),
                 self.storage_key_path.display().to_string(),
             ),
+            (
+                "STORAGE_IDENTITY".to_string(),
+                self.storage_identity.display().to_string(),
+            ),
+            (
+                "STORAGE_KEYS".to_string(),
+                self.storage_keys.display().to_string(),
+            ),
             (
                 "STORAGE_TEMPLATES".to_string(),
                 self.storage_templates.display().to_string(),
             ),
+            (
+                "STRICT_KEY_PERMISSIONS".to_string(),
+                self.strict_key_permissions.to_string(),
+            ),
             (
                 "TEMPLATE_EXT".to_string(),
                 self.template_ext
@@ -157,6 +244,25 @@ impl Settings {
     }
 }
 
+/// Hides credentials (`REPOSITORY_AUTH`, or key material put in `.bakerc` by mistake).
+fn redacted(name: &str, value: &str) -> String {
+    if name == "REPOSITORY_AUTH" || name == "BAKER_KEY" {
+        "********".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Comma separated values, ignoring empty entries.
+pub fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
 fn parse_bool(value: &str) -> Option<bool> {
     match value.to_lowercase().as_str() {
         "true" => Some(true),
@@ -1,5 +1,6 @@
 use crate::logger::Logger;
 use crate::recipe::Instruction;
+use crate::sensitive::SecretString;
 use crate::settings::Settings;
 use crate::storage::{read_file, write_file};
 use std::collections::HashMap;
@@ -27,6 +28,7 @@ pub fn replace(
             template.replace(&instruction.variables)?
         };
 
+        // `replaced` holds decrypted secrets and is wiped once written.
         write_file(&target, &replaced)?;
         apply_permissions(instruction, &target, logger);
         logger.log(&format!(
@@ -69,27 +71,45 @@ impl BakerTemplate {
         }
     }
 
-    pub fn replace(&self, mapping: &HashMap<String, String>) -> Result<String, Box<dyn Error>> {
-        let mut output = String::new();
-        let chars: Vec<char> = self.template.chars().collect();
-        let mut idx = 0;
-        while idx < chars.len() {
-            if chars[idx] == '{' && idx + 1 < chars.len() && chars[idx + 1] == '{' {
-                idx += 2;
-                let mut name = String::new();
-                while idx + 1 < chars.len() && !(chars[idx] == '}' && chars[idx + 1] == '}') {
-                    name.push(chars[idx]);
-                    idx += 1;
-                }
-                if idx + 1 >= chars.len() {
-                    return Err("Unclosed template variable".into());
-                }
-                idx += 2; // skip closing
-                let trimmed = name.trim();
-                if trimmed.starts_with('\\') {
-                    output.push_str("{{");
-                    continue;
+    /// Names referenced through `{{ NAME }}`, normalized like `replace` looks them up.
+    pub fn variables(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut names = Vec::new();
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find("{{") {
+            let after = &rest[start + 2..];
+            let end = after.find("}}").ok_or("Unclosed template variable")?;
+            let trimmed = after[..end].trim();
+            if !trimmed.starts_with('\\') {
+                let key = if self.case_sensitive {
+                    trimmed.to_string()
+                } else {
+                    trimmed.to_lowercase()
+                };
+                if !names.contains(&key) {
+                    names.push(key);
                 }
+            }
+            rest = &after[end + 2..];
+        }
+        Ok(names)
+    }
+
+    /// Renders the template. The pieces are gathered first so the output is allocated once
+    /// and no reallocation leaves a partial copy of a secret behind.
+    pub fn replace(
+        &self,
+        mapping: &HashMap<String, SecretString>,
+    ) -> Result<SecretString, Box<dyn Error>> {
+        let mut pieces: Vec<&str> = Vec::new();
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find("{{") {
+            pieces.push(&rest[..start]);
+            let after = &rest[start + 2..];
+            let end = after.find("}}").ok_or("Unclosed template variable")?;
+            let trimmed = after[..end].trim();
+            if trimmed.starts_with('\\') {
+                pieces.push("{{");
+            } else {
                 let key = if self.case_sensitive {
                     trimmed.to_string()
                 } else {
@@ -98,11 +118,16 @@ impl BakerTemplate {
                 let value = mapping
                     .get(&key)
                     .ok_or_else(|| format!("Missing variable {}", trimmed))?;
-                output.push_str(value);
-            } else {
-                output.push(chars[idx]);
-                idx += 1;
+                pieces.push(value);
             }
+            rest = &after[end + 2..];
+        }
+        pieces.push(rest);
+
+        let length = pieces.iter().map(|piece| piece.len()).sum();
+        let mut output = SecretString::new(String::with_capacity(length));
+        for piece in pieces {
+            output.push_str(piece);
         }
         Ok(output)
     }
@@ -116,7 +141,7 @@ mod tests {
     fn replaces_case_insensitive_by_default() {
         let tpl = BakerTemplate::new("host: {{ HOST }}", false);
         let mut map = HashMap::new();
-        map.insert("host".to_string(), "dev".to_string());
+        map.insert("host".to_string(), "dev".into());
         let out = tpl.replace(&map).unwrap();
         assert_eq!(out, "host: dev");
     }
@@ -128,4 +153,10 @@ mod tests {
         let out = tpl.replace(&map).unwrap();
         assert_eq!(out, "{{ data");
     }
+
+    #[test]
+    fn lists_referenced_variables() {
+        let tpl = BakerTemplate::new("{{ HOST }}:{{PORT}} {{\\ skip }} {{ host }}", false);
+        assert_eq!(tpl.variables().unwrap(), vec!["host", "port"]);
+    }
 }